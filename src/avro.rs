@@ -0,0 +1,581 @@
+extern crate snap;
+
+use byteorder::{BE, ByteOrder};
+use flate2::Crc;
+use flate2::read::DeflateDecoder;
+use serde_json::{Map, Number, Value};
+
+use compression;
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read};
+use std::sync::Arc;
+
+const MAGIC: &[u8] = b"Obj\x01";
+
+/// Avro object container file, read a block at a time.
+/// Blocks are decoded (and decompressed) separately, see `Decoder`.
+pub struct AvroFile {
+    file: Box<dyn BufRead + Send>,
+    sync: [u8; 16],
+    decoder: Arc<Decoder>,
+}
+
+/// Block of `count` encoded records.
+pub struct Block {
+    count: usize,
+    bytes: Vec<u8>,
+}
+
+/// Turns blocks into rows, as the embedded schema describes them.
+pub struct Decoder {
+    schema: Schema,
+    // Named types (records, enums and fixed), by their full names.
+    names: HashMap<String, Schema>,
+    codec: Codec,
+}
+
+enum Codec {
+    Null,
+    Deflate,
+    Snappy,
+    Zstandard,
+}
+
+#[derive(Clone)]
+enum Schema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record(Vec<(String, Schema)>),
+    Enum(Vec<String>),
+    Array(Box<Schema>),
+    Map(Box<Schema>),
+    Union(Vec<Schema>),
+    Fixed(usize),
+    // `timestamp-*` and `date` logical types over int/long, into milliseconds since epoch
+    // (`value * times / by`).
+    Timestamp(i64, i64),
+    // `decimal` logical type over bytes/fixed, with its scale.
+    Decimal(Box<Schema>, i32),
+    // Reference to a named type, looked up while decoding (so that types can refer to themselves).
+    Named(String),
+}
+
+impl AvroFile {
+    /// Reads the header, an error if it is not that of an avro file of records.
+    pub fn open(path: &str) -> Result<Self, String> {
+        let mut file = compression::open(path);
+        let (sync, decoder) = read_header(&mut file).map_err(|e| format!("`{}`: {}", path, e))?;
+        Ok(AvroFile{file, sync, decoder: Arc::new(decoder)})
+    }
+
+    pub fn decoder(&self) -> Arc<Decoder> {
+        self.decoder.clone()
+    }
+}
+
+/// Blocks one by one, up to the end of the file or the first one
+/// that cannot be read (with a warning, as the rest cannot be either).
+impl Iterator for AvroFile {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        let count = match read_long(&mut self.file) {
+            Ok(count) => count,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => {
+                warn!("skipped the rest of an avro file: {}", e);
+                return None;
+            },
+        };
+        let block = read_bytes(&mut self.file).and_then(|bytes| {
+            let mut sync = [0; 16];
+            self.file.read_exact(&mut sync)?;
+            if sync != self.sync {
+                return Err(invalid("avro block is not followed by the sync marker".to_string()));
+            }
+            Ok(bytes)
+        });
+        match block {
+            Ok(bytes) if count >= 0 => Some(Block{count: count as usize, bytes}),
+            Ok(_) => {
+                warn!("skipped the rest of an avro file: negative block count {}", count);
+                None
+            },
+            Err(e) => {
+                warn!("skipped the rest of an avro file, block of {} records: {}", count, e);
+                None
+            },
+        }
+    }
+}
+
+// Magic, metadata (the schema and codec) and the sync marker.
+fn read_header<R: Read>(file: &mut R) -> Result<([u8; 16], Decoder), String> {
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic).map_err(|e| e.to_string())?;
+    if magic != MAGIC {
+        return Err("not an avro object container file".to_string());
+    }
+    let mut meta = HashMap::new();
+    loop {
+        let count = read_count(file).map_err(|e| e.to_string())?;
+        if count == 0 {
+            break;
+        }
+        for _ in 0..count {
+            let key = read_string(file).map_err(|e| e.to_string())?;
+            meta.insert(key, read_bytes(file).map_err(|e| e.to_string())?);
+        }
+    }
+    let mut sync = [0; 16];
+    file.read_exact(&mut sync).map_err(|e| e.to_string())?;
+
+    let schema = meta.get("avro.schema").ok_or("no schema")?;
+    let schema = serde_json::from_slice(schema).map_err(|e| format!("invalid schema: {}", e))?;
+    let mut names = HashMap::new();
+    let schema = parse_schema(&schema, "", &mut names)?;
+    check_names(&schema, &mut names)?;
+    match schema {
+        Schema::Record(_) => (),
+        _ => return Err("does not hold records".to_string()),
+    }
+    let codec = match meta.get("avro.codec").map(|c| &c[..]) {
+        None | Some(b"null") => Codec::Null,
+        Some(b"deflate") => Codec::Deflate,
+        Some(b"snappy") => Codec::Snappy,
+        Some(b"zstandard") => Codec::Zstandard,
+        Some(codec) => return Err(format!("unsupported avro codec `{}`", String::from_utf8_lossy(codec))),
+    };
+    Ok((sync, Decoder{schema, names, codec}))
+}
+
+impl Decoder {
+    /// Records of `block`, with fields of nested records (and maps) flattened
+    /// into `parent.child` keys. Arrays become JSON arrays (multi-value dimensions),
+    /// nulls are left out, just as missing JSON fields are. Logical timestamps
+    /// (and dates) come out in milliseconds, as `auto` and `millis` formats take them.
+    /// Malformed blocks are an error, for the block to be skipped.
+    pub fn rows(&self, block: Block) -> Result<Vec<Map<String, Value>>, String> {
+        let count = block.count;
+        self.decode_block(block).map_err(|e| format!("invalid avro block of {} records: {}", count, e))
+    }
+
+    fn decode_block(&self, block: Block) -> io::Result<Vec<Map<String, Value>>> {
+        let bytes = match self.codec {
+            Codec::Null => block.bytes,
+            Codec::Deflate => {
+                let mut bytes = vec![];
+                DeflateDecoder::new(&block.bytes[..]).read_to_end(&mut bytes)?;
+                bytes
+            },
+            // Followed by a (big-endian) CRC32 of the uncompressed data.
+            Codec::Snappy => {
+                if block.bytes.len() < 4 {
+                    return Err(invalid("snappy block without its checksum".to_string()));
+                }
+                let (compressed, crc) = block.bytes.split_at(block.bytes.len() - 4);
+                let bytes = snap::raw::Decoder::new().decompress_vec(compressed)
+                    .map_err(|e| invalid(e.to_string()))?;
+                let mut checksum = Crc::new();
+                checksum.update(&bytes);
+                if checksum.sum() != BE::read_u32(crc) {
+                    return Err(invalid("snappy block checksum mismatch".to_string()));
+                }
+                bytes
+            },
+            Codec::Zstandard => ::zstd::decode_all(&block.bytes[..])?,
+        };
+        let mut buf = &bytes[..];
+        (0..block.count).map(|_| {
+            let mut row = Map::new();
+            self.flatten(&self.schema, "", &mut buf, &mut row)?;
+            Ok(row)
+        }).collect()
+    }
+
+    // Records (and maps) have their fields put into `row` under `prefix.name`,
+    // anything else goes in whole.
+    fn flatten(&self, schema: &Schema, prefix: &str, buf: &mut &[u8], row: &mut Map<String, Value>) -> io::Result<()> {
+        let key = |name: &str| if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+        match schema {
+            Schema::Record(fields) => for (name, field) in fields {
+                self.flatten(field, &key(name), buf, row)?;
+            },
+            Schema::Map(values) => loop {
+                let count = read_count(buf)?;
+                if count == 0 {
+                    break;
+                }
+                for _ in 0..count {
+                    let name = read_string(buf)?;
+                    self.flatten(values, &key(&name), buf, row)?;
+                }
+            },
+            Schema::Union(branches) => self.flatten(branch(branches, buf)?, prefix, buf, row)?,
+            Schema::Named(name) => self.flatten(&self.names[name], prefix, buf, row)?,
+            _ => match self.decode(schema, buf)? {
+                Value::Null => (),
+                value => {
+                    row.insert(prefix.to_string(), value);
+                },
+            },
+        }
+        Ok(())
+    }
+
+    fn decode(&self, schema: &Schema, buf: &mut &[u8]) -> io::Result<Value> {
+        Ok(match schema {
+            Schema::Null => Value::Null,
+            Schema::Boolean => Value::Bool(take(buf, 1)?[0] != 0),
+            Schema::Int | Schema::Long => Value::from(read_long(buf)?),
+            Schema::Float => {
+                let mut bytes = [0; 4];
+                bytes.copy_from_slice(take(buf, 4)?);
+                double(f32::from_le_bytes(bytes) as f64)
+            },
+            Schema::Double => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(take(buf, 8)?);
+                double(f64::from_le_bytes(bytes))
+            },
+            Schema::Bytes | Schema::String => Value::String(String::from_utf8_lossy(&read_bytes(buf)?).into_owned()),
+            Schema::Fixed(size) => Value::String(String::from_utf8_lossy(take(buf, *size)?).into_owned()),
+            Schema::Enum(symbols) => {
+                let symbol = read_long(buf)?;
+                let symbol = symbols.get(symbol as usize)
+                    .ok_or_else(|| invalid(format!("enum symbol {} out of range", symbol)))?;
+                Value::String(symbol.clone())
+            },
+            Schema::Array(items) => {
+                let mut values = vec![];
+                loop {
+                    let count = read_count(buf)?;
+                    if count == 0 {
+                        break;
+                    }
+                    for _ in 0..count {
+                        values.push(self.decode(items, buf)?);
+                    }
+                }
+                Value::Array(values)
+            },
+            Schema::Record(_) | Schema::Map(_) => {
+                let mut object = Map::new();
+                self.flatten(schema, "", buf, &mut object)?;
+                Value::Object(object)
+            },
+            Schema::Union(branches) => self.decode(branch(branches, buf)?, buf)?,
+            Schema::Timestamp(times, by) => {
+                let value = read_long(buf)?;
+                let value = value.checked_mul(*times).ok_or_else(|| invalid(format!("date {} out of range", value)))?;
+                Value::from(value.div_euclid(*by))
+            },
+            Schema::Decimal(underlying, scale) => {
+                let bytes = match **underlying {
+                    Schema::Fixed(size) => take(buf, size)?.to_vec(),
+                    _ => read_bytes(buf)?,
+                };
+                // Big-endian two's complement.
+                let unscaled = bytes.iter().fold(if bytes.first().map_or(false, |b| *b >= 0x80) { -1i128 } else { 0 }, |n, b| n << 8 | *b as i128);
+                double(unscaled as f64 / 10f64.powi(*scale))
+            },
+            Schema::Named(name) => self.decode(&self.names[name], buf)?,
+        })
+    }
+}
+
+fn parse_schema(value: &Value, namespace: &str, names: &mut HashMap<String, Schema>) -> Result<Schema, String> {
+    let invalid_schema = || format!("invalid avro schema `{}`", value);
+    let (kind, o) = match value {
+        Value::String(kind) => (kind.as_str(), None),
+        Value::Array(branches) => return Ok(Schema::Union(
+            branches.iter().map(|b| parse_schema(b, namespace, names)).collect::<Result<_, _>>()?
+        )),
+        Value::Object(o) => match o.get("type") {
+            Some(Value::String(kind)) => (kind.as_str(), Some(o)),
+            // `{"type": {"type": "array", ...}}`, as fields' types are
+            Some(t) => return parse_schema(t, namespace, names),
+            None => return Err(invalid_schema()),
+        },
+        _ => return Err(invalid_schema()),
+    };
+    let get = |key: &str| o.and_then(|o| o.get(key));
+    let logical = get("logicalType").and_then(|l| l.as_str());
+
+    // Named types (records, enums and fixed) can be referred to by name later on.
+    let mut namespace = namespace.to_string();
+    let mut fullname = None;
+    if let (Some(name), "record") | (Some(name), "error") | (Some(name), "enum") | (Some(name), "fixed")
+        = (get("name").and_then(|n| n.as_str()), kind) {
+        fullname = Some(match (name.rfind('.'), get("namespace").and_then(|n| n.as_str())) {
+            (Some(dot), _) => {
+                namespace = name[..dot].to_string();
+                name.to_string()
+            },
+            (None, Some(ns)) if !ns.is_empty() => {
+                namespace = ns.to_string();
+                format!("{}.{}", ns, name)
+            },
+            (None, _) if !namespace.is_empty() => format!("{}.{}", namespace, name),
+            (None, _) => name.to_string(),
+        });
+    }
+
+    let schema = match (kind, logical) {
+        ("int", Some("date")) => Schema::Timestamp(86_400_000, 1),
+        ("long", Some("timestamp-millis")) | ("long", Some("local-timestamp-millis")) => Schema::Timestamp(1, 1),
+        ("long", Some("timestamp-micros")) | ("long", Some("local-timestamp-micros")) => Schema::Timestamp(1, 1000),
+        ("long", Some("timestamp-nanos")) | ("long", Some("local-timestamp-nanos")) => Schema::Timestamp(1, 1_000_000),
+        ("bytes", Some("decimal")) | ("fixed", Some("decimal")) => {
+            let scale = get("scale").and_then(|s| s.as_i64()).unwrap_or(0) as i32;
+            let mut underlying = o.ok_or_else(invalid_schema)?.clone();
+            underlying.remove("logicalType");
+            Schema::Decimal(Box::new(parse_schema(&Value::Object(underlying), &namespace, names)?), scale)
+        },
+        ("null", _) => Schema::Null,
+        ("boolean", _) => Schema::Boolean,
+        ("int", _) => Schema::Int,
+        ("long", _) => Schema::Long,
+        ("float", _) => Schema::Float,
+        ("double", _) => Schema::Double,
+        ("bytes", _) => Schema::Bytes,
+        ("string", _) => Schema::String,
+        ("record", _) | ("error", _) => Schema::Record(
+            get("fields").and_then(|f| f.as_array()).ok_or_else(invalid_schema)?
+                .iter()
+                .map(|f| Ok((
+                    f["name"].as_str().ok_or_else(invalid_schema)?.to_string(),
+                    parse_schema(&f["type"], &namespace, names)?,
+                )))
+                .collect::<Result<_, String>>()?
+        ),
+        ("enum", _) => Schema::Enum(
+            get("symbols").and_then(|s| s.as_array()).ok_or_else(invalid_schema)?
+                .iter()
+                .map(|s| s.as_str().map(|s| s.to_string()).ok_or_else(invalid_schema))
+                .collect::<Result<_, _>>()?
+        ),
+        ("array", _) => Schema::Array(Box::new(parse_schema(get("items").ok_or_else(invalid_schema)?, &namespace, names)?)),
+        ("map", _) => Schema::Map(Box::new(parse_schema(get("values").ok_or_else(invalid_schema)?, &namespace, names)?)),
+        ("fixed", _) => Schema::Fixed(get("size").and_then(|s| s.as_u64()).ok_or_else(invalid_schema)? as usize),
+        (name, _) if name.contains('.') || namespace.is_empty() => Schema::Named(name.to_string()),
+        (name, _) => Schema::Named(format!("{}.{}", namespace, name)),
+    };
+    if let Some(fullname) = fullname {
+        names.insert(fullname, schema.clone());
+    }
+    Ok(schema)
+}
+
+// Makes sure every referenced type is defined, those referred to by their
+// name alone (rather than within the namespace they are used in) included.
+fn check_names(schema: &Schema, names: &mut HashMap<String, Schema>) -> Result<(), String> {
+    match schema {
+        Schema::Record(fields) => for (_, field) in fields {
+            check_names(field, names)?;
+        },
+        Schema::Array(schema) | Schema::Map(schema) | Schema::Decimal(schema, _) => check_names(schema, names)?,
+        Schema::Union(branches) => for branch in branches {
+            check_names(branch, names)?;
+        },
+        Schema::Named(fullname) if !names.contains_key(fullname) => {
+            let name = &fullname[fullname.rfind('.').map_or(0, |dot| dot + 1)..];
+            let named = names.get(name).cloned().ok_or(format!("unknown avro type `{}`", name))?;
+            names.insert(fullname.clone(), named);
+        },
+        _ => (),
+    }
+    Ok(())
+}
+
+fn double(v: f64) -> Value {
+    Number::from_f64(v).map(Value::Number).unwrap_or(Value::Null)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> io::Result<&'a [u8]> {
+    if n > buf.len() {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let (taken, rest) = buf.split_at(n);
+    *buf = rest;
+    Ok(taken)
+}
+
+fn branch<'a>(branches: &'a [Schema], buf: &mut &[u8]) -> io::Result<&'a Schema> {
+    let branch = read_long(buf)?;
+    branches.get(branch as usize).ok_or_else(|| invalid(format!("union branch {} out of range", branch)))
+}
+
+// Zig-zag encoded variable length integer.
+fn read_long<R: Read>(r: &mut R) -> io::Result<i64> {
+    let mut n = 0u64;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0];
+        r.read_exact(&mut byte)?;
+        if shift > 63 {
+            return Err(invalid("variable length integer over 64 bits".to_string()));
+        }
+        n |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok((n >> 1) as i64 ^ -((n & 1) as i64));
+        }
+        shift += 7;
+    }
+}
+
+// Number of items in a block of an array or a map. Negative counts are
+// followed by the size of the block in bytes, which is of no use here.
+fn read_count<R: Read>(r: &mut R) -> io::Result<usize> {
+    let count = read_long(r)?;
+    if count < 0 {
+        read_long(r)?;
+    }
+    Ok(count.unsigned_abs() as usize)
+}
+
+// Length prefixed, read without trusting the length up front.
+fn read_bytes<R: Read>(r: &mut R) -> io::Result<Vec<u8>> {
+    let len = read_long(r)?;
+    if len < 0 {
+        return Err(invalid(format!("negative length {}", len)));
+    }
+    let mut bytes = vec![];
+    r.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() as i64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+fn read_string<R: Read>(r: &mut R) -> io::Result<String> {
+    String::from_utf8(read_bytes(r)?).map_err(|e| invalid(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoder(schema: &str, codec: Codec) -> Decoder {
+        let mut names = HashMap::new();
+        let schema = parse_schema(&serde_json::from_str(schema).unwrap(), "", &mut names).unwrap();
+        check_names(&schema, &mut names).unwrap();
+        Decoder{schema, names, codec}
+    }
+
+    #[test]
+    fn recursive_records_are_decoded() {
+        let decoder = decoder(r#"{"type": "record", "name": "Row", "namespace": "test", "fields": [
+            {"name": "ts", "type": "long"},
+            {"name": "node", "type": {"type": "record", "name": "Node", "fields": [
+                {"name": "v", "type": "long"},
+                {"name": "next", "type": ["null", "Node"]}
+            ]}}
+        ]}"#, Codec::Null);
+        // ts 5, node 1 -> 2 -> null
+        let rows = decoder.rows(Block{count: 1, bytes: vec![0x0a, 0x02, 0x02, 0x04, 0x00]}).unwrap();
+        assert_eq!(Value::Object(rows[0].clone()), serde_json::json!({"ts": 5, "node.v": 1, "node.next.v": 2}));
+    }
+
+    #[test]
+    fn malformed_blocks_are_errors() {
+        let schema = r#"{"type": "record", "name": "Row", "fields": [{"name": "s", "type": ["null", "string"]}]}"#;
+        let block = |bytes: &[u8]| Block{count: 1, bytes: bytes.to_vec()};
+
+        let record = [0x02, 0x06, b'a', b'b', b'c'];
+        let mut snappy = snap::raw::Encoder::new().compress_vec(&record).unwrap();
+        let mut crc = Crc::new();
+        crc.update(&record);
+        snappy.extend_from_slice(&crc.sum().to_be_bytes());
+        let snappy_decoder = decoder(schema, Codec::Snappy);
+        assert_eq!(snappy_decoder.rows(block(&snappy)).unwrap()[0]["s"], "abc");
+        // No room for the checksum, and a checksum not matching.
+        assert!(snappy_decoder.rows(block(&snappy[..3])).is_err());
+        let last = snappy.len() - 1;
+        snappy[last] ^= 1;
+        assert!(snappy_decoder.rows(block(&snappy)).is_err());
+
+        let null_decoder = decoder(schema, Codec::Null);
+        assert_eq!(null_decoder.rows(block(&record)).unwrap()[0]["s"], "abc");
+        // Cut short, with no union branch 2 and with a negative length.
+        assert!(null_decoder.rows(block(&record[..4])).is_err());
+        assert!(null_decoder.rows(block(&[0x04])).is_err());
+        assert!(null_decoder.rows(block(&[0x02, 0x01])).is_err());
+    }
+
+    fn long(n: i64) -> Vec<u8> {
+        let mut n = ((n << 1) ^ (n >> 63)) as u64;
+        let mut bytes = vec![];
+        while n >= 0x80 {
+            bytes.push((n as u8 & 0x7f) | 0x80);
+            n >>= 7;
+        }
+        bytes.push(n as u8);
+        bytes
+    }
+
+    fn prefixed(bytes: &[u8]) -> Vec<u8> {
+        let mut prefixed = long(bytes.len() as i64);
+        prefixed.extend_from_slice(bytes);
+        prefixed
+    }
+
+    // Container of `schema` with a block of a record of `s` for each of `blocks`.
+    fn container(schema: &str, blocks: &[&str]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.extend(long(1));
+        file.extend(prefixed(b"avro.schema"));
+        file.extend(prefixed(schema.as_bytes()));
+        file.extend(long(0));
+        file.extend_from_slice(&[7; 16]);
+        for s in blocks {
+            file.extend(long(1));
+            file.extend(prefixed(&prefixed(s.as_bytes())));
+            file.extend_from_slice(&[7; 16]);
+        }
+        file
+    }
+
+    fn blocks(name: &str, file: &[u8]) -> Result<usize, String> {
+        let path = std::env::temp_dir().join(format!("dsp-{}-{}.avro", std::process::id(), name));
+        std::fs::write(&path, file).unwrap();
+        let blocks = AvroFile::open(path.to_str().unwrap()).map(|avro| avro.count());
+        std::fs::remove_file(&path).unwrap();
+        blocks
+    }
+
+    #[test]
+    fn malformed_files_end_at_the_last_whole_block() {
+        let schema = r#"{"type": "record", "name": "Row", "fields": [{"name": "s", "type": "string"}]}"#;
+        let file = container(schema, &["a", "b", "c"]);
+        assert_eq!(blocks("whole", &file), Ok(3));
+        // Cut within the last block, and within its sync marker.
+        assert_eq!(blocks("cut-block", &file[..file.len() - 18]), Ok(2));
+        assert_eq!(blocks("cut-sync", &file[..file.len() - 1]), Ok(2));
+        // Blocks take 20 bytes: count, length, the record and the sync marker.
+        let mut unsynced = file.clone();
+        unsynced[file.len() - 20 - 16] = 8;
+        assert_eq!(blocks("unsynced", &unsynced), Ok(1));
+
+        assert!(blocks("magic", &file[1..]).is_err());
+        assert!(blocks("header", &file[..20]).is_err());
+        assert!(blocks("not-records", &container(r#""string""#, &[])).is_err());
+        assert!(blocks("unknown-type", &container(r#"{"type": "record", "name": "Row", "fields": [{"name": "s", "type": "Other"}]}"#, &[])).is_err());
+        assert!(blocks("invalid-schema", &container(r#"{"type": "record", "name": "Row"}"#, &[])).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use std::io::{Cursor, Write};
+
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use concise::CONCISE;
+use roaring::RoaringBitmap;
+
+use conf;
+
+/// Bitmap of row numbers, in the format chosen with `--bitmap`.
+#[derive(Clone)]
+pub enum Bitmap {
+    Concise(CONCISE),
+    Roaring(RoaringBitmap),
+}
+
+impl Bitmap {
+    pub fn new() -> Self {
+        match conf::vals.bitmap {
+            conf::BitmapType::Concise => Bitmap::Concise(CONCISE::new()),
+            conf::BitmapType::Roaring => Bitmap::Roaring(RoaringBitmap::new()),
+        }
+    }
+
+    /// Rows have to be added in ascending order.
+    pub fn append(&mut self, row: usize) {
+        match self {
+            Bitmap::Concise(bitmap) => bitmap.append(row as i32),
+            Bitmap::Roaring(bitmap) => {
+                bitmap.push(row as u32);
+            },
+        }
+    }
+
+    /// Number of bytes `write` takes.
+    pub fn size(&self) -> usize {
+        match self {
+            Bitmap::Concise(bitmap) => bitmap.words_view().iter().count() * 4,
+            Bitmap::Roaring(bitmap) => if bitmap.is_empty() { 0 } else { bitmap.serialized_size() },
+        }
+    }
+
+    /// Writes the bitmap the way its `ObjectStrategy` reads it back,
+    /// an empty one takes no bytes at all.
+    pub fn write(&self, out: &mut dyn Write) {
+        match self {
+            Bitmap::Concise(bitmap) => for word in bitmap.words_view() {
+                out.write_i32::<BE>(word.0).unwrap();
+            },
+            // ImmutableRoaringBitmap uses the portable serialization format.
+            Bitmap::Roaring(bitmap) => if !bitmap.is_empty() {
+                bitmap.serialize_into(out).unwrap();
+            },
+        }
+    }
+}
+
+/// Rows set in a bitmap written by `Bitmap::write` (as `bitmap_type`).
+pub fn rows(bitmap_type: conf::BitmapType, data: &[u8]) -> Vec<usize> {
+    if data.is_empty() {
+        return vec![];
+    }
+    match bitmap_type {
+        conf::BitmapType::Roaring => RoaringBitmap::deserialize_from(data).unwrap()
+            .iter().map(|row| row as usize).collect(),
+        conf::BitmapType::Concise => {
+            let mut rows = vec![];
+            let mut block = 0;
+            let mut words = Cursor::new(data);
+            while let Ok(word) = words.read_u32::<BE>() {
+                if word & 0x8000_0000 != 0 {
+                    // Literal, 31 bits of rows.
+                    rows.extend((0..31).filter(|bit| word & (1 << bit) != 0).map(|bit| block * 31 + bit));
+                    block += 1;
+                    continue;
+                }
+                // Sequence of blocks of all 0s or all 1s, the first one with a bit flipped.
+                let ones = word & 0x4000_0000 != 0;
+                let flipped = (word >> 25) & 0x1f;
+                let blocks = (word & 0x01ff_ffff) as usize + 1;
+                let flip = if flipped == 0 { None } else { Some(block * 31 + flipped as usize - 1) };
+                if ones {
+                    rows.extend((block * 31..(block + blocks) * 31).filter(|row| Some(*row) != flip));
+                } else {
+                    rows.extend(flip);
+                }
+                block += blocks;
+            }
+            rows
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::{ByteOrder, LE};
+
+    #[test]
+    fn roaring_bitmaps_are_written_in_the_portable_format() {
+        let rows_set: Vec<usize> = (0..100).chain(70_000..70_010).chain(vec![1 << 20]).collect();
+        let mut bitmap = Bitmap::Roaring(RoaringBitmap::new());
+        for row in &rows_set {
+            bitmap.append(*row);
+        }
+        let mut data = vec![];
+        bitmap.write(&mut data);
+
+        assert_eq!(data.len(), bitmap.size());
+        // SERIAL_COOKIE_NO_RUNCONTAINER, followed by the number of containers.
+        assert_eq!((LE::read_u32(&data), LE::read_u32(&data[4..])), (12346, 3));
+        assert_eq!(rows(conf::BitmapType::Roaring, &data), rows_set);
+
+        let mut empty = vec![];
+        Bitmap::Roaring(RoaringBitmap::new()).write(&mut empty);
+        assert!(empty.is_empty());
+        assert!(rows(conf::BitmapType::Roaring, &empty).is_empty());
+    }
+}
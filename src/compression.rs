@@ -0,0 +1,133 @@
+extern crate bzip2;
+extern crate xz2;
+
+use flate2::bufread::MultiGzDecoder;
+use self::bzip2::bufread::MultiBzDecoder;
+use self::xz2::bufread::XzDecoder;
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+/// Compression of an input file, as told by its extension
+/// or, failing that, by the magic bytes it starts with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl Compression {
+    pub fn from_extension(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "gz" | "gzip" => Some(Compression::Gzip),
+            "zst" | "zstd" => Some(Compression::Zstd),
+            "bz2" => Some(Compression::Bzip2),
+            "xz" => Some(Compression::Xz),
+            _ => None,
+        }
+    }
+
+    fn from_magic(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if bytes.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Compression::Zstd)
+        } else if bytes.starts_with(b"BZh") {
+            Some(Compression::Bzip2)
+        } else if bytes.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Compression::Xz)
+        } else {
+            None
+        }
+    }
+}
+
+/// Opens `path` (stdin, if `-`), decompressing it on the fly when it is compressed.
+/// Files of concatenated streams (as `pigz` or `pbzip2` write) are read whole.
+pub fn open(path: &str) -> Box<dyn BufRead + Send> {
+    let file: Box<dyn Read + Send> = match path {
+        "-" => Box::new(io::stdin()),
+        _ => Box::new(File::open(path).unwrap()),
+    };
+    let mut file = BufReader::new(file);
+    let compression = match Compression::from_extension(Path::new(path)) {
+        Some(compression) => Some(compression),
+        None => Compression::from_magic(file.fill_buf().unwrap()),
+    };
+    match compression {
+        None => Box::new(file),
+        Some(Compression::Gzip) => Box::new(BufReader::new(MultiGzDecoder::new(file))),
+        Some(Compression::Zstd) => Box::new(BufReader::new(::zstd::Decoder::with_buffer(file).unwrap())),
+        Some(Compression::Bzip2) => Box::new(BufReader::new(MultiBzDecoder::new(file))),
+        Some(Compression::Xz) => Box::new(BufReader::new(XzDecoder::new_multi_decoder(file))),
+    }
+}
+
+/// Name of the file at `path`, less its extension and the compression one,
+/// if any (`day.json.gz` is `day`).
+pub fn stem(path: &str) -> String {
+    let path = Path::new(path);
+    let path = match Compression::from_extension(path) {
+        Some(_) => Path::new(path.file_stem().unwrap()),
+        None => path,
+    };
+    path.file_stem().unwrap().to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Write;
+
+    // `text` compressed as two concatenated streams.
+    fn compressed(compression: Compression, text: &[u8]) -> Vec<u8> {
+        let (first, second) = text.split_at(text.len() / 2);
+        let mut out = vec![];
+        for part in &[first, second] {
+            match compression {
+                Compression::Gzip => {
+                    let mut encoder = ::flate2::write::GzEncoder::new(vec![], ::flate2::Compression::default());
+                    encoder.write_all(part).unwrap();
+                    out.extend(encoder.finish().unwrap());
+                },
+                Compression::Zstd => out.extend(::zstd::stream::encode_all(*part, 3).unwrap()),
+                Compression::Bzip2 => {
+                    let mut encoder = bzip2::write::BzEncoder::new(vec![], bzip2::Compression::default());
+                    encoder.write_all(part).unwrap();
+                    out.extend(encoder.finish().unwrap());
+                },
+                Compression::Xz => {
+                    let mut encoder = xz2::write::XzEncoder::new(vec![], 6);
+                    encoder.write_all(part).unwrap();
+                    out.extend(encoder.finish().unwrap());
+                },
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn compressed_files_are_read_whole_by_extension_or_magic_bytes() {
+        let dir = std::env::temp_dir().join(format!("dsp-{}-compression", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let text: Vec<u8> = (0..10_000).flat_map(|n| format!("{{\"page\": \"p{}\"}}\n", n).into_bytes()).collect();
+
+        let kinds = [(Compression::Gzip, "gz"), (Compression::Zstd, "zst"), (Compression::Bzip2, "bz2"), (Compression::Xz, "xz")];
+        for (compression, extension) in &kinds {
+            let data = compressed(*compression, &text);
+            for name in &[format!("day.json.{}", extension), "day.json".to_string()] {
+                let path = dir.join(name);
+                fs::write(&path, &data).unwrap();
+                let mut read = vec![];
+                open(path.to_str().unwrap()).read_to_end(&mut read).unwrap();
+                assert!(read == text, "`{}` compressed with {:?}", name, compression);
+            }
+            assert_eq!(stem(&format!("data/day.json.{}", extension)), "day");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
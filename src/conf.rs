@@ -1,23 +1,334 @@
+use serde_json::Value;
 use structopt::StructOpt;
 
+use granularity::Granularity;
+use segment;
+use spec;
+use timestamp::{TimestampFormat, parse_instant};
+
 use std::path::PathBuf;
+use std::str::FromStr;
 
 arg_enum! {
-    #[derive(Clone, Copy)]
+    #[derive(Clone, Copy, PartialEq)]
     pub enum Compression {
-        None = 0xff,
-        LZ4 = 0x1,
+        None,
+        Uncompressed,
+        LZF,
+        LZ4,
+        ZSTD,
+    }
+}
+
+impl Compression {
+    /// Druid's `CompressionStrategy` id. `None` still writes (uncompressed)
+    /// blocks for numeric columns, only string columns skip them.
+    pub fn id(&self) -> u8 {
+        match self {
+            Compression::None | Compression::Uncompressed => 0xff,
+            Compression::LZF => 0x0,
+            Compression::LZ4 => 0x1,
+            Compression::ZSTD => 0x2,
+        }
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy)]
+    pub enum BitmapType {
+        Concise,
+        Roaring,
+    }
+}
+
+impl BitmapType {
+    /// `bitmapSerdeFactory` of columns and of the whole index.
+    pub fn to_json(&self) -> Value {
+        match self {
+            BitmapType::Concise => json!({"type": "concise"}),
+            BitmapType::Roaring => json!({"type": "roaring"}),
+        }
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy)]
+    pub enum LongEncoding {
+        Longs,
+        Auto,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy)]
+    pub enum NullHandling {
+        Default,
+        SQL,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy, PartialEq)]
+    pub enum InputFormat {
+        JSON,
+        CSV,
+        TSV,
+        Parquet,
+        Avro,
+    }
+}
+
+impl InputFormat {
+    /// Extension of the files picked up from a directory.
+    pub fn extension(&self) -> &str {
+        match self {
+            InputFormat::JSON => "json",
+            InputFormat::CSV => "csv",
+            InputFormat::TSV => "tsv",
+            InputFormat::Parquet => "parquet",
+            InputFormat::Avro => "avro",
+        }
+    }
+}
+
+/// Single (ASCII) character, as csv/tsv delimiters and quotes are.
+pub fn parse_byte(s: &str) -> Result<u8, String> {
+    match s.as_bytes() {
+        [b] if b.is_ascii() => Ok(*b),
+        b"\\t" => Ok(b'\t'),
+        _ => Err(format!("`{}` is not a single character", s)),
+    }
+}
+
+/// Smoosh file size, from 1MiB (for dictionaries and bitmaps to fit in) up to
+/// what Druid can map, `i32::MAX`.
+pub fn parse_smoosh_size(s: &str) -> Result<usize, String> {
+    let min = 1 << 20;
+    match s.parse() {
+        Ok(size) if size >= min && size <= i32::MAX as usize => Ok(size),
+        Ok(_) => Err(format!("`{}` is not between {} and {}", s, min, i32::MAX)),
+        Err(e) => Err(format!("`{}`: {}", s, e)),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregator {
+    Count,
+    // `longSum` or `doubleSum`, depending on the type of the input column
+    Sum,
+    LongSum,
+    DoubleSum,
+    LongMin,
+    LongMax,
+    DoubleMin,
+    DoubleMax,
+    FloatSum,
+    FloatMin,
+    FloatMax,
+}
+
+impl Aggregator {
+    /// Druid name of the aggregator over a column of `kind`.
+    pub fn type_name(&self, kind: DimensionType) -> String {
+        let prefix = match kind {
+            DimensionType::Long => "long",
+            DimensionType::Float => "float",
+            _ => "double",
+        };
+        match self {
+            Aggregator::Count => "count".to_string(),
+            Aggregator::LongMin | Aggregator::DoubleMin | Aggregator::FloatMin => format!("{}Min", prefix),
+            Aggregator::LongMax | Aggregator::DoubleMax | Aggregator::FloatMax => format!("{}Max", prefix),
+            _ => format!("{}Sum", prefix),
+        }
+    }
+
+    /// Type of the column the aggregator produces, given whether its field holds floating point values.
+    pub fn kind(&self, float_field: bool) -> DimensionType {
+        let float = if vals.float_metrics { DimensionType::Float } else { DimensionType::Double };
+        match self {
+            Aggregator::Sum if float_field => float,
+            Aggregator::DoubleSum | Aggregator::DoubleMin | Aggregator::DoubleMax => float,
+            Aggregator::FloatSum | Aggregator::FloatMin | Aggregator::FloatMax => DimensionType::Float,
+            _ => DimensionType::Long,
+        }
+    }
+
+    /// Aggregator used to combine already aggregated values
+    pub fn combining(&self) -> Self {
+        match self {
+            Aggregator::Count => Aggregator::LongSum,
+            other => *other,
+        }
+    }
+}
+
+/// Metric given as `[NAME=]TYPE[:FIELD]`, e.g. `longSum:bytes`, `users=count`.
+/// Bare field name (e.g. `bytes`) means a sum of that field.
+#[derive(Clone, Debug)]
+pub struct Metric {
+    pub name: String,
+    pub aggregator: Aggregator,
+    pub field: String,
+}
+
+impl FromStr for Metric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, spec) = match s.find('=') {
+            Some(i) => (Some(&s[..i]), &s[i + 1..]),
+            None => (None, s),
+        };
+        let (kind, field) = match spec.find(':') {
+            Some(i) => (&spec[..i], Some(&spec[i + 1..])),
+            None => (spec, None),
+        };
+        let aggregator = match kind {
+            "count" => Aggregator::Count,
+            "longSum" => Aggregator::LongSum,
+            "doubleSum" => Aggregator::DoubleSum,
+            "longMin" => Aggregator::LongMin,
+            "longMax" => Aggregator::LongMax,
+            "doubleMin" => Aggregator::DoubleMin,
+            "doubleMax" => Aggregator::DoubleMax,
+            "floatSum" => Aggregator::FloatSum,
+            "floatMin" => Aggregator::FloatMin,
+            "floatMax" => Aggregator::FloatMax,
+            _ if field.is_none() => Aggregator::Sum,
+            _ => return Err(format!("unknown aggregator `{}`", kind)),
+        };
+        let field = match (aggregator, field) {
+            (_, Some(field)) => field,
+            (Aggregator::Count, None) => "",
+            (Aggregator::Sum, None) => kind,
+            (_, None) => name.ok_or(format!("aggregator `{}` needs a field", kind))?,
+        };
+        let name = match (name, aggregator) {
+            (Some(name), _) => name,
+            (None, Aggregator::Count) => "count",
+            (None, _) => field,
+        };
+        if name.is_empty() {
+            return Err(format!("metric `{}` needs a name", s));
+        }
+        Ok(Metric{name: name.to_string(), aggregator, field: field.to_string()})
+    }
+}
+
+/// Druid's column types (`float` being 32-bit).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DimensionType {
+    String,
+    Long,
+    Float,
+    Double,
+}
+
+impl FromStr for DimensionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "string" => Ok(DimensionType::String),
+            "long" => Ok(DimensionType::Long),
+            "float" => Ok(DimensionType::Float),
+            "double" => Ok(DimensionType::Double),
+            _ => Err(format!("unknown dimension type `{}`", s)),
+        }
     }
 }
 
+#[derive(Clone, Debug)]
+pub struct Dimension {
+    pub name: String,
+    pub kind: DimensionType,
+}
+
+impl FromStr for Dimension {
+    type Err = String;
+
+    /// `NAME[:TYPE]`, e.g. `page` or `port:long`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, kind) = match s.rfind(':') {
+            Some(i) => (&s[..i], s[i + 1..].parse()?),
+            None => (s, DimensionType::String),
+        };
+        if name.is_empty() {
+            return Err(format!("dimension `{}` needs a name", s));
+        }
+        Ok(Dimension{name: name.to_string(), kind})
+    }
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    /// Merges segments written by dsp (in place of FILE) into one,
+    /// rolling them up again unless told otherwise
+    #[structopt(name = "merge")]
+    Merge {
+        /// Segment directories (with `meta.smoosh` and its `.smoosh` files)
+        #[structopt(name = "SEGMENT", parse(from_os_str), raw(required = "true"))]
+        segments: Vec<PathBuf>,
+    },
+}
+
 #[derive(StructOpt)]
 #[structopt(name = "dsp")]
 pub struct Conf {
+    /// `NAME[:TYPE]`, TYPE being string (default), long, float or double
     #[structopt(short, long)]
-    pub dimensions: Vec<String>,
+    pub dimensions: Vec<Dimension>,
 
+    /// `[NAME=]TYPE[:FIELD]`, TYPE being one of count, longSum, doubleSum,
+    /// longMin, longMax, doubleMin, doubleMax, floatSum, floatMin, floatMax
     #[structopt(short, long)]
-    pub metrics: Vec<String>,
+    pub metrics: Vec<Metric>,
+
+    /// Do not combine rows with equal timestamp and dimensions
+    #[structopt(long = "no-rollup")]
+    pub no_rollup: bool,
+
+    #[structopt(long = "timestamp-column", default_value = "timestamp")]
+    pub timestamp_column: String,
+
+    /// auto, iso, posix (seconds), millis, micro, nano or a `chrono` pattern
+    #[structopt(long = "timestamp-format", default_value = "millis")]
+    pub timestamp_format: TimestampFormat,
+
+    /// Used for rows without a (valid) timestamp, otherwise they are skipped
+    #[structopt(long = "timestamp-missing", parse(try_from_str = "parse_instant"))]
+    pub timestamp_missing: Option<i64>,
+
+    #[structopt(long = "segment-granularity", default_value = "DAY",
+        raw(
+            possible_values = "&Granularity::variants()",
+            case_insensitive = "true",
+        ),
+    )]
+    pub segment_granularity: Granularity,
+
+    /// Timestamps are truncated to this before rolling up
+    #[structopt(long = "query-granularity", default_value = "NONE",
+        raw(
+            possible_values = "&Granularity::variants()",
+            case_insensitive = "true",
+        ),
+    )]
+    pub query_granularity: Granularity,
+
+    /// Druid ingestion spec (or just its `dataSchema`) to take defaults from
+    #[structopt(long)]
+    pub spec: Option<String>,
+
+    /// Store floating point metrics as 32-bit floats instead of doubles
+    #[structopt(long = "float-metrics")]
+    pub float_metrics: bool,
+
+    /// Largest `.smoosh` file, entries go to the next file when they do not fit.
+    /// At least 1MiB
+    #[structopt(long = "smoosh-size", raw(default_value = "&MAX_SMOOSH_SIZE"), parse(try_from_str = "parse_smoosh_size"))]
+    pub smoosh_size: usize,
 
     #[structopt(short, long, default_value = "output", parse(from_os_str))]
     pub output: PathBuf,
@@ -30,19 +341,247 @@ pub struct Conf {
     )]
     pub compression: Compression,
 
+    /// Compression of string dimension ids and numeric dimensions, defaults to `--compression`
+    #[structopt(long = "dimension-compression",
+        raw(
+            possible_values = "&Compression::variants()",
+            case_insensitive = "true",
+        ),
+    )]
+    pub dimension_compression: Option<Compression>,
+
+    /// Compression of metrics and timestamps, defaults to `--compression`
+    #[structopt(long = "metric-compression",
+        raw(
+            possible_values = "&Compression::variants()",
+            case_insensitive = "true",
+        ),
+    )]
+    pub metric_compression: Option<Compression>,
+
+    #[structopt(short, long, default_value = "concise",
+        raw(
+            possible_values = "&BitmapType::variants()",
+            case_insensitive = "true",
+        ),
+    )]
+    pub bitmap: BitmapType,
+
+    /// Front code string dictionaries in buckets of this many values (1, 2, 4, ..., 128)
+    #[structopt(long = "front-coded",
+        raw(possible_values = r#"&["1", "2", "4", "8", "16", "32", "64", "128"]"#),
+    )]
+    pub front_coded: Option<u8>,
+
+    /// Store long columns as they are, or delta/table encoded when smaller (auto)
+    #[structopt(long = "long-encoding", default_value = "longs",
+        raw(
+            possible_values = "&LongEncoding::variants()",
+            case_insensitive = "true",
+        ),
+    )]
+    pub long_encoding: LongEncoding,
+
+    /// Store missing values as `0`/`""` (default) or as nulls (SQL compatible)
+    #[structopt(short, long = "null-handling", default_value = "default",
+        raw(
+            possible_values = "&NullHandling::variants()",
+            case_insensitive = "true",
+        ),
+    )]
+    pub null_handling: NullHandling,
+
     #[structopt(short, long,
         raw(possible_values = r#"&["1", "2", "3", "4", "5", "6", "7", "8", "9"]"#),
     )]
     pub zip: Option<u8>,
 
-    #[structopt(short, long, raw(default_value = "&numcpus"))]
+    #[structopt(short, long, raw(default_value = "&NUM_CPUS"))]
     pub threads: usize,
 
+    /// Sort and spill rows to disk every this many, merging them back at the end
+    #[structopt(long = "max-rows-in-memory")]
+    pub max_rows_in_memory: Option<usize>,
+
+    /// Where spilled rows go, defaults to the system temporary directory
+    #[structopt(long = "spill-dir", parse(from_os_str))]
+    pub spill_dir: Option<PathBuf>,
+
+    /// Format of FILE (or of the files picked up from it, when a directory)
+    #[structopt(long = "input-format", default_value = "json",
+        raw(
+            possible_values = "&InputFormat::variants()",
+            case_insensitive = "true",
+        ),
+    )]
+    pub input_format: InputFormat,
+
+    /// Field delimiter of csv/tsv input, defaults to `,` (csv) or a tab (tsv)
+    #[structopt(long, parse(try_from_str = "parse_byte"))]
+    pub delimiter: Option<u8>,
+
+    /// Quote character of csv/tsv input
+    #[structopt(long, default_value = "\"", parse(try_from_str = "parse_byte"))]
+    pub quote: u8,
+
+    /// Column names of csv/tsv input, otherwise taken from its header row
+    #[structopt(long, raw(use_delimiter = "true"))]
+    pub columns: Vec<String>,
+
+    /// Rows of csv/tsv input skipped before the header row (or the data, with `--columns`)
+    #[structopt(long = "skip-header-rows", default_value = "0")]
+    pub skip_header_rows: usize,
+
+    /// Descend into subdirectories of the directories given
+    #[structopt(short, long)]
+    pub recursive: bool,
+
+    /// Write segments of all the inputs as one set, to `<output>/<NAME>`,
+    /// rather than one set per input file
+    #[structopt(long, value_name = "NAME")]
+    pub combine: Option<String>,
+
+    /// Files, directories or glob patterns (`-` for stdin), required unless merging.
+    /// Files can be gzip, zstd, bzip2 or xz compressed
     #[structopt(name = "FILE")]
-    pub file: String,
+    pub files: Vec<String>,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Conf {
+    #[cfg_attr(test, allow(dead_code))]
+    fn load() -> Self {
+        let matches = Conf::clap().get_matches();
+        let mut conf = Conf::from_clap(&matches);
+        if let Some(path) = conf.spec.clone() {
+            if let Err(e) = spec::apply(&mut conf, &matches, &path) {
+                clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit();
+            }
+        }
+        if cfg!(not(feature = "parquet")) && conf.input_format == InputFormat::Parquet {
+            clap::Error::with_description(
+                "parquet input needs dsp built with the `parquet` feature",
+                clap::ErrorKind::InvalidValue,
+            ).exit();
+        }
+        if conf.files.is_empty() && conf.command.is_none() {
+            clap::Error::with_description(
+                "The following required arguments were not provided:\n    <FILE>",
+                clap::ErrorKind::MissingRequiredArgument,
+            ).exit();
+        }
+        if let Some(Command::Merge{segments}) = &conf.command {
+            let segments = segments.clone();
+            if let Err(e) = segment::apply(&mut conf, &matches, &segments) {
+                clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit();
+            }
+        }
+        // Rows truncated to a coarser query granularity could go before their segment.
+        if conf.query_granularity > conf.segment_granularity {
+            clap::Error::with_description(
+                &format!(
+                    "query granularity {} is coarser than segment granularity {}",
+                    conf.query_granularity.name(), conf.segment_granularity.name(),
+                ),
+                clap::ErrorKind::InvalidValue,
+            ).exit();
+        }
+        conf
+    }
+
+    /// Whether rows at `ts` can be bucketed by both granularities (and written),
+    /// rows that cannot are skipped as those without a valid timestamp are.
+    pub fn bucketable(&self, ts: i64) -> bool {
+        self.query_granularity.truncate(ts)
+            .and_then(|ts| self.segment_granularity.truncate(ts))
+            .and_then(|start| self.segment_granularity.next(start))
+            .is_some()
+    }
+
+    /// Configured metrics, with the default `count` added if there is none
+    /// (and the name is not taken).
+    pub fn metrics(&self) -> Vec<Metric> {
+        let mut metrics = self.metrics.clone();
+        if !metrics.iter().any(|m| m.aggregator == Aggregator::Count || m.name == "count") {
+            metrics.push(Metric{
+                name: "count".to_string(),
+                aggregator: Aggregator::Count,
+                field: String::new(),
+            });
+        }
+        metrics
+    }
+
+    pub fn dimension_compression(&self) -> Compression {
+        self.dimension_compression.unwrap_or(self.compression)
+    }
+
+    pub fn metric_compression(&self) -> Compression {
+        self.metric_compression.unwrap_or(self.compression)
+    }
+
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter.unwrap_or(match self.input_format {
+            InputFormat::TSV => b'\t',
+            _ => b',',
+        })
+    }
+
+    /// Type of `name`, if it is a configured dimension.
+    pub fn dimension_type(&self, name: &str) -> Option<DimensionType> {
+        self.dimensions.iter().find(|d| d.name == name).map(|d| d.kind)
+    }
 }
 
 lazy_static! {
-    static ref numcpus: String = num_cpus::get().to_string();
-    pub static ref vals: Conf = Conf::from_args();
+    static ref NUM_CPUS: String = num_cpus::get().to_string();
+    static ref MAX_SMOOSH_SIZE: String = i32::MAX.to_string();
+}
+
+#[cfg(not(test))]
+lazy_static! {
+    pub static ref vals: Conf = Conf::load();
+}
+
+// Unit tests run with the defaults, whatever arguments the test harness got.
+#[cfg(test)]
+lazy_static! {
+    pub static ref vals: Conf = Conf::from_iter(&["dsp", "-"]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_are_named_after_their_field_unless_given_a_name() {
+        let metric = |s: &str| s.parse::<Metric>().map(|m| (m.name, m.aggregator, m.field));
+        let owned = |name: &str, aggregator, field: &str| Ok((name.to_string(), aggregator, field.to_string()));
+        assert_eq!(metric("count"), owned("count", Aggregator::Count, ""));
+        assert_eq!(metric("rows=count"), owned("rows", Aggregator::Count, ""));
+        assert_eq!(metric("bytes"), owned("bytes", Aggregator::Sum, "bytes"));
+        assert_eq!(metric("longMax:latency"), owned("latency", Aggregator::LongMax, "latency"));
+        assert_eq!(metric("slowest=doubleMax:latency"), owned("slowest", Aggregator::DoubleMax, "latency"));
+        assert_eq!(metric("latency=floatMin"), owned("latency", Aggregator::FloatMin, "latency"));
+        assert!(metric("longMin").is_err());
+        assert!(metric("median:latency").is_err());
+
+        let metrics = Conf::from_iter(&["dsp", "-", "--metrics", "bytes"]).metrics();
+        assert_eq!(metrics.iter().map(|m| m.name.as_str()).collect::<Vec<_>>(), ["bytes", "count"]);
+        assert_eq!(Aggregator::Sum.type_name(DimensionType::Long), "longSum");
+        assert_eq!(Aggregator::DoubleMin.type_name(DimensionType::Float), "floatMin");
+    }
+
+    #[test]
+    fn smoosh_sizes_out_of_range_are_rejected() {
+        let smoosh_size = |size: &str| Conf::from_iter_safe(&["dsp", "--smoosh-size", size, "-"]).map(|conf| conf.smoosh_size);
+        assert_eq!(Conf::from_iter(&["dsp", "-"]).smoosh_size, i32::MAX as usize);
+        assert_eq!(smoosh_size("1048576").ok(), Some(1 << 20));
+        assert_eq!(smoosh_size("2147483647").ok(), Some(i32::MAX as usize));
+        for size in &["0", "1048575", "2147483648", "4294967296", "-1", "1MB"] {
+            assert!(smoosh_size(size).is_err(), "{}", size);
+        }
+    }
 }
@@ -0,0 +1,267 @@
+extern crate chrono;
+
+use self::chrono::{Datelike, NaiveDate, NaiveDateTime};
+
+use serde_json::Value;
+
+use std::str::FromStr;
+
+static MINUTE: i64 = 60_000;
+static HOUR: i64 = 3_600_000;
+static DAY: i64 = 86_400_000;
+// 1970-01-01 in days from 0001-01-01 (as counted by chrono)
+static EPOCH_DAYS: i64 = 719_163;
+
+// JodaUtils.ETERNITY, which is what Druid uses for ALL granularity
+static MIN_INSTANT: i64 = i64::MIN / 2;
+static MAX_INSTANT: i64 = i64::MAX / 2;
+
+// In order, from the finest to the coarsest.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Granularity {
+    None,
+    Second,
+    Minute,
+    FiveMinute,
+    TenMinute,
+    FifteenMinute,
+    ThirtyMinute,
+    Hour,
+    SixHour,
+    EightHour,
+    Day,
+    Week,
+    Month,
+    Quarter,
+    Year,
+    All,
+}
+
+static NAMES: [(&str, Granularity); 16] = [
+    ("NONE", Granularity::None),
+    ("SECOND", Granularity::Second),
+    ("MINUTE", Granularity::Minute),
+    ("FIVE_MINUTE", Granularity::FiveMinute),
+    ("TEN_MINUTE", Granularity::TenMinute),
+    ("FIFTEEN_MINUTE", Granularity::FifteenMinute),
+    ("THIRTY_MINUTE", Granularity::ThirtyMinute),
+    ("HOUR", Granularity::Hour),
+    ("SIX_HOUR", Granularity::SixHour),
+    ("EIGHT_HOUR", Granularity::EightHour),
+    ("DAY", Granularity::Day),
+    ("WEEK", Granularity::Week),
+    ("MONTH", Granularity::Month),
+    ("QUARTER", Granularity::Quarter),
+    ("YEAR", Granularity::Year),
+    ("ALL", Granularity::All),
+];
+
+// ISO 8601 periods of the granularities above, for `{"type": "period"}`
+static PERIODS: [(&str, Granularity); 14] = [
+    ("PT1S", Granularity::Second),
+    ("PT1M", Granularity::Minute),
+    ("PT5M", Granularity::FiveMinute),
+    ("PT10M", Granularity::TenMinute),
+    ("PT15M", Granularity::FifteenMinute),
+    ("PT30M", Granularity::ThirtyMinute),
+    ("PT1H", Granularity::Hour),
+    ("PT6H", Granularity::SixHour),
+    ("PT8H", Granularity::EightHour),
+    ("P1D", Granularity::Day),
+    ("P1W", Granularity::Week),
+    ("P1M", Granularity::Month),
+    ("P3M", Granularity::Quarter),
+    ("P1Y", Granularity::Year),
+];
+
+impl Granularity {
+    /// Only periods matching one of the named granularities are supported.
+    pub fn from_period(period: &str) -> Option<Self> {
+        PERIODS.iter().find(|(p, _)| p.eq_ignore_ascii_case(period)).map(|(_, g)| *g)
+    }
+
+    pub fn variants() -> Vec<&'static str> {
+        NAMES.iter().map(|(name, _)| *name).collect()
+    }
+
+    pub fn name(&self) -> &'static str {
+        NAMES.iter().find(|(_, g)| g == self).unwrap().0
+    }
+
+    // Length of granularities that do not depend on the calendar.
+    fn duration(&self) -> Option<i64> {
+        match self {
+            Granularity::None => Some(1),
+            Granularity::Second => Some(1000),
+            Granularity::Minute => Some(MINUTE),
+            Granularity::FiveMinute => Some(5 * MINUTE),
+            Granularity::TenMinute => Some(10 * MINUTE),
+            Granularity::FifteenMinute => Some(15 * MINUTE),
+            Granularity::ThirtyMinute => Some(30 * MINUTE),
+            Granularity::Hour => Some(HOUR),
+            Granularity::SixHour => Some(6 * HOUR),
+            Granularity::EightHour => Some(8 * HOUR),
+            Granularity::Day => Some(DAY),
+            _ => None,
+        }
+    }
+
+    /// Start of the bucket `ts` falls into, `None` if either is out of the range of dates.
+    pub fn truncate(&self, ts: i64) -> Option<i64> {
+        let date = to_date(ts)?;
+        let start = match self.duration() {
+            Some(duration) => ts - ts.rem_euclid(duration),
+            None => match self {
+                // 1970-01-01 was a Thursday, weeks start on Monday
+                Granularity::Week => ts - (ts + 3 * DAY).rem_euclid(7 * DAY),
+                Granularity::Month => from_date(date.year(), date.month())?,
+                Granularity::Quarter => from_date(date.year(), date.month() - (date.month() - 1) % 3)?,
+                Granularity::Year => from_date(date.year(), 1)?,
+                _ => return Some(MIN_INSTANT),
+            },
+        };
+        to_date(start).map(|_| start)
+    }
+
+    /// Start of the bucket following the one starting at `start`,
+    /// `None` if it is out of the range of dates.
+    pub fn next(&self, start: i64) -> Option<i64> {
+        let date = to_date(start)?;
+        let months = match self.duration() {
+            Some(duration) => return to_date(start + duration).map(|_| start + duration),
+            None => match self {
+                Granularity::Week => return to_date(start + 7 * DAY).map(|_| start + 7 * DAY),
+                Granularity::Month => 1,
+                Granularity::Quarter => 3,
+                Granularity::Year => 12,
+                _ => return Some(MAX_INSTANT),
+            },
+        };
+        let month = date.month0() + months;
+        from_date(date.year() + (month / 12) as i32, month % 12 + 1)
+    }
+
+    /// Form used in segment metadata.
+    pub fn to_json(&self) -> Value {
+        match self {
+            Granularity::None => json!({"type": "none"}),
+            Granularity::All => json!({"type": "all"}),
+            other => json!(other.name()),
+        }
+    }
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NAMES.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|(_, g)| *g)
+            .ok_or(format!("unknown granularity `{}`", s))
+    }
+}
+
+/// Formats `ts` the way Druid does in interval strings. Has to be within
+/// the range of dates, as rows (and missing values) out of it are rejected.
+pub fn format_instant(ts: i64) -> String {
+    match ts {
+        ts if ts == MIN_INSTANT => "-146136543-09-08T08:23:32.096Z".to_string(),
+        ts if ts == MAX_INSTANT => "146140482-04-24T15:36:27.903Z".to_string(),
+        ts => to_datetime(ts)
+            .unwrap_or_else(|| panic!("instant {} out of the range of dates", ts))
+            .format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string(),
+    }
+}
+
+/// Whether `ts` is within the range of dates (that can be formatted).
+pub fn in_range(ts: i64) -> bool {
+    to_datetime(ts).is_some()
+}
+
+fn to_datetime(ts: i64) -> Option<NaiveDateTime> {
+    let ms = ts.rem_euclid(DAY);
+    to_date(ts)?.and_hms_milli_opt(
+        (ms / HOUR) as u32,
+        (ms % HOUR / 60_000) as u32,
+        (ms % 60_000 / 1000) as u32,
+        (ms % 1000) as u32,
+    )
+}
+
+fn to_date(ts: i64) -> Option<NaiveDate> {
+    let days = ts.div_euclid(DAY) + EPOCH_DAYS;
+    if days < i32::MIN as i64 || days > i32::MAX as i64 {
+        return None;
+    }
+    NaiveDate::from_num_days_from_ce_opt(days as i32)
+}
+
+fn from_date(year: i32, month: u32) -> Option<i64> {
+    let days = NaiveDate::from_ymd_opt(year, month, 1)?.num_days_from_ce() as i64;
+    Some((days - EPOCH_DAYS) * DAY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_out_of_the_range_of_dates_are_none() {
+        // 2020-09-14T10:30:00Z
+        let ts = 1_600_079_400_000;
+        assert_eq!(Granularity::Month.truncate(ts), Some(1_598_918_400_000));
+        assert_eq!(Granularity::Month.next(1_598_918_400_000), Some(1_601_510_400_000));
+        assert_eq!(Granularity::Quarter.truncate(ts), Some(1_593_561_600_000));
+        assert_eq!(Granularity::All.truncate(ts), Some(MIN_INSTANT));
+
+        for granularity in &[Granularity::Hour, Granularity::Week, Granularity::Month, Granularity::Year] {
+            assert_eq!(granularity.truncate(i64::MAX), None);
+            assert_eq!(granularity.truncate(i64::MIN), None);
+        }
+        // The last year there is has no next one.
+        let last = from_date(NaiveDate::MAX.year(), 1).unwrap();
+        assert_eq!(Granularity::Year.truncate(last + DAY), Some(last));
+        assert_eq!(Granularity::Year.next(last), None);
+    }
+
+    #[test]
+    fn timestamps_are_truncated_to_the_start_of_their_bucket() {
+        // 2020-09-14T10:37:12.500Z, a Monday
+        let ts = 1_600_079_832_500;
+        let starts = [
+            (Granularity::None, ts),
+            (Granularity::Second, 1_600_079_832_000),
+            (Granularity::Minute, 1_600_079_820_000),
+            (Granularity::FifteenMinute, 1_600_079_400_000),
+            (Granularity::Hour, 1_600_077_600_000),
+            (Granularity::SixHour, 1_600_063_200_000),
+            (Granularity::EightHour, 1_600_070_400_000),
+            (Granularity::Day, 1_600_041_600_000),
+            (Granularity::Week, 1_600_041_600_000),
+            (Granularity::Year, 1_577_836_800_000),
+        ];
+        for (granularity, start) in &starts {
+            assert_eq!(granularity.truncate(ts), Some(*start), "{:?}", granularity);
+        }
+        // The Sunday before is in the week before.
+        assert_eq!(Granularity::Week.truncate(ts - DAY), Some(1_600_041_600_000 - 7 * DAY));
+        // Before the epoch.
+        assert_eq!(Granularity::Day.truncate(-1), Some(-DAY));
+        assert_eq!(Granularity::Week.truncate(0), Some(-3 * DAY));
+
+        assert_eq!("fifteen_minute".parse(), Ok(Granularity::FifteenMinute));
+        assert_eq!(Granularity::from_period("PT6H"), Some(Granularity::SixHour));
+        assert_eq!(Granularity::from_period("PT2H"), None);
+        assert_eq!(Granularity::None.to_json(), json!({"type": "none"}));
+        assert_eq!(Granularity::Hour.to_json(), json!("HOUR"));
+    }
+
+    #[test]
+    fn granularities_are_ordered_by_coarseness() {
+        assert!(Granularity::None < Granularity::Second);
+        assert!(Granularity::Hour < Granularity::Day);
+        assert!(Granularity::Week < Granularity::Month);
+        assert!(Granularity::Year < Granularity::All);
+    }
+}
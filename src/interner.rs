@@ -1,15 +1,24 @@
 extern crate byteorder;
-extern crate concise;
 extern crate indexmap;
 
-use byteorder::{BE, LE, WriteBytesExt};
-use self::concise::CONCISE;
+use byteorder::{BE, LE, ReadBytesExt, WriteBytesExt};
 use self::indexmap::IndexSet;
 
-use std::io::Write;
+use std::cmp::Ordering;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, Write};
+use std::ops::Range;
 
+use bitmap::Bitmap;
 use conf;
-use compress;
+use smoosh::Smoosher;
+use {int_size, write_compressed, write_meta};
+
+// GenericIndexedWriter.fileSizeLimit
+static FILE_SIZE_LIMIT: usize = i32::MAX as usize;
+
+// DictionaryEncodedColumnPartSerde.Feature masks
+static MULTI_VALUE_V3: u32 = 0x2;
 
 #[derive(Debug)]
 pub struct IS(Vec<ISF>);
@@ -19,137 +28,332 @@ impl IS {
         IS(vec![ISF::new()])
     }
 
+    pub fn new_multi() -> Self {
+        IS(vec![ISF::new_multi()])
+    }
+
+    /// Column of ids into `keys`, `ends` being row boundaries of a multi-value one.
+    pub fn from_ids(keys: Vec<Option<String>>, indexes: Vec<usize>, ends: Option<Vec<usize>>) -> Self {
+        IS(vec![ISF{keys: keys.into_iter().collect(), indexes, ends}])
+    }
+
     pub fn add_s(&mut self, s: String) {
-        self.0[0].add_s(s);
+        self.0.last_mut().unwrap().add_s(s);
+    }
+
+    pub fn add_ms(&mut self, ss: Vec<String>) {
+        self.0.last_mut().unwrap().add_ms(ss);
+    }
+
+    pub fn add_null(&mut self) {
+        self.0.last_mut().unwrap().add_null();
+    }
+
+    pub fn make_multi(&mut self) {
+        for isf in &mut self.0 {
+            isf.make_multi();
+        }
     }
 
+    // Parts are kept in order, rows are added to the last one.
     pub fn append(&mut self, other: &mut IS) {
-        self.0.push(other.0.swap_remove(0));
+        self.0.append(&mut other.0);
     }
 
     pub fn len(&self) -> usize {
         self.0.iter().map(|isf| isf.len()).sum()
     }
 
-    pub fn write(&self, writer: &mut Write) {
+    /// Values of a single-value column, row by row across all the parts.
+    #[cfg(test)]
+    pub fn values(&self) -> Vec<Option<String>> {
+        self.0.iter()
+            .flat_map(|isf| isf.indexes.iter().map(move |i| isf.keys.get_index(*i).unwrap().clone()))
+            .collect()
+    }
+
+    /// Adds the column to the smoosh, followed by any extra entries it needs
+    /// (values of GenericIndexed sections too large to be written inline,
+    /// or with which the column would not fit in a smoosh chunk).
+    pub fn write(&self, smoosher: &mut Smoosher, name: &str, meta: &str, compression: conf::Compression) -> Result<(), String> {
         let data = &self.0[0];
 
-        let mut index_header = Vec::with_capacity(data.keys.len() * 4);
-        let mut index_items = Vec::with_capacity(data.keys.len() * 4);
-        for k in &data.keys {
-            index_items.write_u32::<BE>(0).unwrap(); // nullness marker
-            index_items.write_all(k.as_bytes()).unwrap();
-            index_header.write_u32::<BE>(index_items.len() as u32).unwrap();
+        let vint = VInt::new(data.keys.len(), compression);
+
+        let mut bitmaps = vec![Bitmap::new(); data.keys.len()];
+        match &data.ends {
+            None => for (v, i) in data.indexes.iter().enumerate() {
+                bitmaps[*i].append(v);
+            },
+            Some(ends) => {
+                let mut start = 0;
+                for (v, end) in ends.iter().enumerate() {
+                    let mut last = None;
+                    // Values within a row are sorted, so duplicates are adjacent.
+                    for i in &data.indexes[start..*end] {
+                        if last != Some(*i) {
+                            bitmaps[*i].append(v);
+                        }
+                        last = Some(*i);
+                    }
+                    start = *end;
+                }
+            },
         }
 
-        let vint = VInt::new(data.keys.len());
+        let keys = &data.keys;
+        let limit = file_size_limit(smoosher.max_chunk_size());
+        let mut dictionary = GenericIndexed::new(name, keys.iter().map(|k| match k {
+            Some(k) => k.len() + 4,
+            None => 4,
+        }), true, limit)?;
+        let write_key = |i: usize, writer: &mut dyn Write| match keys.get_index(i).unwrap() {
+            Some(k) => {
+                writer.write_u32::<BE>(0).unwrap(); // nullness marker
+                writer.write_all(k.as_bytes()).unwrap();
+            },
+            None => writer.write_i32::<BE>(-1).unwrap(), // NULL_VALUE_SIZE_MARKER
+        };
+        let mut inverted = GenericIndexed::new(&format!("{}.inverted", name), bitmaps.iter().map(|b| b.size() + 4), false, limit)?;
+        let write_bitmap = |i: usize, writer: &mut dyn Write| {
+            writer.write_i32::<BE>(0).unwrap();
+            bitmaps[i].write(writer);
+        };
 
-        let mut index_values = Vec::with_capacity(data.len() * vint.size);
-        let mut bitmaps = vec![CONCISE::new(); data.keys.len()];
-        for (v, i) in data.indexes.iter().enumerate() {
-            vint.write_value(&mut index_values, *i);
-            bitmaps[*i].append(v as i32);
+        let write_column = |writer: &mut BufWriter<File>, dictionary: &GenericIndexed, inverted: &GenericIndexed| {
+            write_meta(writer, meta);
+
+            match compression {
+                conf::Compression::None => match data.ends {
+                    None => writer.write_u8(0).unwrap(), // VERSION (UNCOMPRESSED_SINGLE_VALUE)
+                    Some(_) => writer.write_u8(1).unwrap(), // VERSION (UNCOMPRESSED_MULTI_VALUE)
+                },
+                _ => {
+                    writer.write_u8(2).unwrap(); // VERSION (COMPRESSED)
+                    match data.ends {
+                        // flags (stores info about bitmap/multivalue,
+                        // our desired one for single values turns out to be `0`).
+                        None => writer.write_u32::<BE>(0).unwrap(),
+                        Some(_) => writer.write_u32::<BE>(MULTI_VALUE_V3).unwrap(),
+                    }
+                },
+            }
+
+            match conf::vals.front_coded {
+                Some(bucket_size) => write_front_coded(writer, keys, bucket_size),
+                None => dictionary.write(writer, &write_key),
+            }
+
+            match (compression, &data.ends) {
+                (conf::Compression::None, None) => {
+                    let num_padding = 4 - vint.size;
+
+                    writer.write_u8(0).unwrap(); // VERSION
+                    writer.write_u8(vint.size as u8).unwrap(); // numBytes
+                    writer.write_u32::<BE>(
+                        int_size(data.indexes.len() * vint.size + num_padding, name),
+                    ).unwrap();
+                    for i in &data.indexes {
+                        vint.write_value(writer, *i);
+                    }
+                    writer.write_all(&vec![0; num_padding]).unwrap();
+                },
+                (conf::Compression::None, Some(ends)) => {
+                    let num_padding = 4 - vint.size;
+
+                    writer.write_u8(1).unwrap(); // VERSION
+                    writer.write_u8(vint.size as u8).unwrap(); // numBytes
+                    writer.write_u32::<BE>(
+                        int_size(ends.len() * 4 + data.indexes.len() * vint.size + num_padding + 4, name),
+                    ).unwrap(); // + Integer.BYTES
+                    writer.write_u32::<BE>(ends.len() as u32).unwrap(); // numRows
+                    for end in ends {
+                        writer.write_u32::<BE>(int_size(end * vint.size, name)).unwrap();
+                    }
+                    for i in &data.indexes {
+                        vint.write_value(writer, *i);
+                    }
+                    writer.write_all(&vec![0; num_padding]).unwrap();
+                },
+                (_, None) => {
+                    write_vsize_compressed(writer, &data.indexes, &vint, compression);
+                },
+                (_, Some(ends)) => {
+                    writer.write_u8(3).unwrap(); // VERSION (V3CompressedVSizeColumnarMultiIntsSupplier)
+                    write_offsets_compressed(writer, ends, compression);
+                    write_vsize_compressed(writer, &data.indexes, &vint, compression);
+                },
+            }
+
+            inverted.write(writer, &write_bitmap);
+        };
+
+        let front_coded = conf::vals.front_coded.is_some();
+        if let Err(e) = smoosher.add_with(name, |writer| write_column(writer, &dictionary, &inverted)) {
+            // Too large with the dictionary and bitmaps inline, so they go to entries of their own.
+            if (front_coded || dictionary.power.is_some()) && inverted.power.is_some() {
+                return Err(e);
+            }
+            if !front_coded {
+                dictionary.split(limit)?;
+            }
+            inverted.split(limit)?;
+            smoosher.add_with(name, |writer| write_column(writer, &dictionary, &inverted))?;
         }
 
-        let mut bitmap_header = Vec::with_capacity(bitmaps.len() * 4);
-        let mut bitmap_values = Vec::with_capacity(bitmaps.len() * 4);
-        bitmap_header.write_u32::<BE>(data.keys.len() as u32).unwrap();
-        for bitmap in bitmaps {
-            bitmap_values.write_i32::<BE>(0).unwrap();
-            for word in bitmap.words_view() {
-                bitmap_values.write_i32::<BE>(word.0).unwrap();
+        if !front_coded {
+            dictionary.write_files(smoosher, &write_key)?;
+        }
+        inverted.write_files(smoosher, &write_bitmap)
+    }
+
+    /// Writes keys and rows in a compact form, read back with `load`.
+    /// Has to be called after `sort`.
+    pub fn persist(&self, writer: &mut dyn Write) {
+        let data = &self.0[0];
+        writer.write_u64::<LE>(data.keys.len() as u64).unwrap();
+        for key in &data.keys {
+            match key {
+                Some(k) => {
+                    writer.write_i32::<LE>(k.len() as i32).unwrap();
+                    writer.write_all(k.as_bytes()).unwrap();
+                },
+                None => writer.write_i32::<LE>(-1).unwrap(),
             }
-            bitmap_header.write_u32::<BE>(bitmap_values.len() as u32).unwrap();
         }
+        writer.write_u64::<LE>(data.indexes.len() as u64).unwrap();
+        for i in &data.indexes {
+            writer.write_u32::<LE>(*i as u32).unwrap();
+        }
+        match &data.ends {
+            None => writer.write_u8(0).unwrap(),
+            Some(ends) => {
+                writer.write_u8(1).unwrap();
+                writer.write_u64::<LE>(ends.len() as u64).unwrap();
+                for end in ends {
+                    writer.write_u64::<LE>(*end as u64).unwrap();
+                }
+            },
+        }
+    }
 
-        match conf::vals.compression {
-            conf::Compression::None => {
-                let num_padding = vec![0; 4 - vint.size];
+    pub fn load(reader: &mut dyn Read) -> Self {
+        let mut keys = IndexSet::new();
+        for _ in 0..reader.read_u64::<LE>().unwrap() {
+            let len = reader.read_i32::<LE>().unwrap();
+            if len < 0 {
+                keys.insert(None);
+                continue;
+            }
+            let mut key = vec![0; len as usize];
+            reader.read_exact(&mut key).unwrap();
+            keys.insert(Some(String::from_utf8(key).unwrap()));
+        }
+        let len = reader.read_u64::<LE>().unwrap() as usize;
+        let indexes = (0..len).map(|_| reader.read_u32::<LE>().unwrap() as usize).collect();
+        let ends = match reader.read_u8().unwrap() {
+            0 => None,
+            _ => {
+                let len = reader.read_u64::<LE>().unwrap() as usize;
+                Some((0..len).map(|_| reader.read_u64::<LE>().unwrap() as usize).collect())
+            },
+        };
+        IS(vec![ISF{keys, indexes, ends}])
+    }
 
-                writer.write_u8(0).unwrap(); // VERSION (UNCOMPRESSED_SINGLE_VALUE)
-                writer.write_u8(1).unwrap(); // VERSION_ONE
-                writer.write_u8(1).unwrap(); // REVERSE_LOOKUP_ALLOWED
-                writer.write_u32::<BE>(
-                    index_header.len() as u32 + index_items.len() as u32 + 4
-                ).unwrap(); // + Integer.BYTES
-                writer.write_u32::<BE>(data.keys.len() as u32).unwrap(); // numWritten
-                writer.write_all(&index_header).unwrap();
-                writer.write_all(&index_items).unwrap();
-                writer.write_u8(0).unwrap(); // VERSION
-                writer.write_u8(vint.size as u8).unwrap(); // numBytes
-                writer.write_u32::<BE>(
-                    (data.len() * vint.size + num_padding.len()) as u32,
-                ).unwrap();
-                writer.write_all(&index_values).unwrap();
-                writer.write_all(&num_padding).unwrap();
+    /// Rearranges rows, so that row `i` becomes what was row `rows[i]`,
+    /// rows not listed are dropped. Has to be called after `sort`.
+    pub fn permute(&mut self, rows: &[usize]) {
+        let isf = &mut self.0[0];
+        match isf.ends.take() {
+            None => {
+                isf.indexes = rows.iter().map(|p| isf.indexes[*p]).collect();
             },
-            conf::Compression::LZ4 => {
-                let chunk_factor = 65536 / 2_usize.pow((vint.size - 1) as u32);
-
-                let mut index_header_c = Vec::with_capacity(index_values.len() * 4);
-                let mut index_values_c = Vec::with_capacity(index_values.len());
-                // This is written to header, so that we get proper size later on.
-                index_header_c.write_u32::<BE>(
-                    (data.len() as f64 / chunk_factor as f64).ceil() as u32,
-                ).unwrap();
-                for chunk in index_values.chunks(65536) {
-                    compress(&mut index_values_c, chunk);
-                    index_header_c.write_u32::<BE>(index_values_c.len() as u32).unwrap();
+            Some(ends) => {
+                let mut indexes = Vec::with_capacity(isf.indexes.len());
+                let mut new_ends = Vec::with_capacity(rows.len());
+                for p in rows {
+                    indexes.extend(&isf.indexes[isf.row_start(&ends, *p)..ends[*p]]);
+                    new_ends.push(indexes.len());
                 }
-
-                writer.write_u8(2).unwrap(); // VERSION (COMPRESSED)
-                writer.write_u32::<BE>(0).unwrap(); // flags (stores info about bitmap/multivalue,
-                                                    // our desired one turns out to be `0`).
-                writer.write_u8(1).unwrap(); // VERSION_ONE
-                writer.write_u8(1).unwrap(); // REVERSE_LOOKUP_ALLOWED
-                writer.write_u32::<BE>(
-                    index_header.len() as u32 + index_items.len() as u32 + 4
-                ).unwrap(); // + Integer.BYTES
-                writer.write_u32::<BE>(data.keys.len() as u32).unwrap(); // numWritten
-                writer.write_all(&index_header).unwrap();
-                writer.write_all(&index_items).unwrap();
-                writer.write_u8(2).unwrap(); // VERSION
-                writer.write_u8(vint.size as u8).unwrap();
-                writer.write_u32::<BE>(data.len() as u32).unwrap();
-                writer.write_u32::<BE>(chunk_factor as u32).unwrap();
-                writer.write_u8(conf::vals.compression as u8).unwrap();
-                writer.write_u8(1).unwrap(); // VERSION_ONE
-                writer.write_u8(0).unwrap(); // REVERSE_LOOKUP_DISALLOWED
-                writer.write_u32::<BE>((index_header_c.len() + index_values_c.len()) as u32).unwrap();
-                writer.write_all(&index_header_c).unwrap();
-                writer.write_all(&index_values_c).unwrap();
+                isf.indexes = indexes;
+                isf.ends = Some(new_ends);
             },
         }
+    }
 
-        writer.write_u8(1).unwrap(); // VERSION
-        writer.write_u8(0).unwrap(); // REVERSE_LOOKUP_DISALLOWED
-        writer.write_u32::<BE>(
-            (bitmap_header.len() + bitmap_values.len()) as u32,
-        ).unwrap();
-        writer.write_all(&bitmap_header).unwrap();
-        writer.write_all(&bitmap_values).unwrap();
+    /// Copies a range of rows, with a dictionary containing only values they use.
+    /// Has to be called after `sort`.
+    pub fn select(&self, rows: Range<usize>) -> Self {
+        let isf = &self.0[0];
+        let (mut newisf, values) = match &isf.ends {
+            None => (ISF::new(), &isf.indexes[rows]),
+            Some(ends) => {
+                let start = isf.row_start(ends, rows.start);
+                let end = if rows.end == 0 { 0 } else { ends[rows.end - 1] };
+                let newends = ends[rows].iter().map(|e| e - start).collect();
+                (ISF{keys: IndexSet::new(), indexes: vec![], ends: Some(newends)}, &isf.indexes[start..end])
+            },
+        };
+        newisf.indexes = values.iter().map(|i| {
+            newisf.keys.insert_full(isf.keys.get_index(*i).unwrap().clone()).0
+        }).collect();
+        IS(vec![newisf])
     }
 
-    pub fn sort_and_permute(&mut self, permutation: &[usize]) {
-        self.sort();
-        self.0[0].indexes = permutation.iter().map(|p| self.0[0].indexes[*p]).collect();
+    /// Compares rows by their values. Has to be called after `sort`.
+    pub fn cmp_rows(&self, a: usize, b: usize) -> Ordering {
+        let isf = &self.0[0];
+        match &isf.ends {
+            None => isf.indexes[a].cmp(&isf.indexes[b]),
+            Some(ends) => {
+                let row_a = &isf.indexes[isf.row_start(ends, a)..ends[a]];
+                let row_b = &isf.indexes[isf.row_start(ends, b)..ends[b]];
+                row_a.cmp(row_b)
+            },
+        }
     }
 
-    fn sort(&mut self) {
-        // TODO: Get the .union method going
-        let mut newisf = ISF::new();
+    /// Values of a row, sorted (a single-value one has just one, null included).
+    /// Has to be called after `sort`.
+    pub fn row<'a>(&'a self, row: usize) -> impl Iterator<Item = &'a Option<String>> + 'a {
+        let isf = &self.0[0];
+        let indexes = match &isf.ends {
+            None => &isf.indexes[row..row + 1],
+            Some(ends) => &isf.indexes[isf.row_start(ends, row)..ends[row]],
+        };
+        indexes.iter().map(move |i| isf.keys.get_index(*i).unwrap())
+    }
+
+    /// Merges all the parts into one, with sorted dictionary
+    /// (the union of all the parts' ones).
+    pub fn sort(&mut self) {
+        let mut newisf = match self.0[0].ends {
+            None => ISF::new(),
+            Some(_) => ISF::new_multi(),
+        };
         for isf in &self.0 {
             for d in &isf.keys {
                 newisf.keys.insert(d.clone());
             }
         }
-        newisf.keys.sort();
+        newisf.keys.sort(); // `None` goes first, so null gets id 0, as Druid expects
 
         for isf in &self.0 {
-            for i in &isf.indexes {
-                let v = isf.keys.get_index(*i).unwrap();
-                let (ci, _) = newisf.keys.get_full(v).unwrap();
-                newisf.indexes.push(ci);
+            // Ids of the part's keys in the merged dictionary.
+            let remap: Vec<usize> = isf.keys.iter().map(|k| newisf.keys.get_full(k).unwrap().0).collect();
+            newisf.indexes.extend(isf.indexes.iter().map(|i| remap[*i]));
+            if let (Some(newends), Some(ends)) = (&mut newisf.ends, &isf.ends) {
+                let offset = newends.last().cloned().unwrap_or(0);
+                newends.extend(ends.iter().map(|e| e + offset));
+            }
+        }
+        if let Some(ends) = &newisf.ends {
+            let mut start = 0;
+            for end in ends {
+                // Druid's default `multiValueHandling` is SORTED_ARRAY.
+                newisf.indexes[start..*end].sort_unstable();
+                start = *end;
             }
         }
 
@@ -158,54 +362,459 @@ impl IS {
 }
 
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 struct ISF {
-    keys: IndexSet<String>,
+    keys: IndexSet<Option<String>>,
     indexes: Vec<usize>,
+    // Row boundaries in `indexes`, only present for multi-value columns.
+    ends: Option<Vec<usize>>,
 }
 
 impl ISF {
     fn new() -> Self {
-        Self{keys: IndexSet::new(), indexes: vec![]}
+        Self{keys: IndexSet::new(), indexes: vec![], ends: None}
+    }
+
+    fn new_multi() -> Self {
+        Self{keys: IndexSet::new(), indexes: vec![], ends: Some(vec![])}
     }
 
     fn add_s(&mut self, s: String) {
-        let (i, _) = self.keys.insert_full(s);
+        let (i, _) = self.keys.insert_full(null_if_empty(s));
+        self.indexes.push(i);
+        if let Some(ends) = &mut self.ends {
+            ends.push(self.indexes.len());
+        }
+    }
+
+    fn add_ms(&mut self, ss: Vec<String>) {
+        if ss.is_empty() {
+            return self.add_null();
+        }
+        for s in ss {
+            let (i, _) = self.keys.insert_full(null_if_empty(s));
+            self.indexes.push(i);
+        }
+        if let Some(ends) = &mut self.ends {
+            ends.push(self.indexes.len());
+        }
+    }
+
+    fn add_null(&mut self) {
+        let (i, _) = self.keys.insert_full(None);
         self.indexes.push(i);
+        if let Some(ends) = &mut self.ends {
+            ends.push(self.indexes.len());
+        }
+    }
+
+    fn make_multi(&mut self) {
+        if self.ends.is_none() {
+            self.ends = Some((1..=self.indexes.len()).collect());
+        }
     }
 
     fn len(&self) -> usize {
-        self.indexes.len()
+        match &self.ends {
+            None => self.indexes.len(),
+            Some(ends) => ends.len(),
+        }
+    }
+
+    fn row_start(&self, ends: &[usize], row: usize) -> usize {
+        if row == 0 { 0 } else { ends[row - 1] }
     }
 }
 
+// With default value null handling, Druid does not distinguish "" from null.
+fn null_if_empty(s: String) -> Option<String> {
+    match conf::vals.null_handling {
+        conf::NullHandling::Default if s.is_empty() => None,
+        _ => Some(s),
+    }
+}
+
+// GenericIndexed of values of known sizes (each including its nullness marker).
+// Version 1 has everything inline, version 2 (used when that would not fit in an
+// int addressed buffer or a smoosh chunk) moves values to `<name>_value_<n>` and their offsets to
+// `<name>_header` entries, added with `write_files`.
+struct GenericIndexed {
+    name: String,
+    ends: Vec<usize>,
+    reverse_lookup: bool,
+    // log2 of values per file, for version 2.
+    power: Option<usize>,
+}
+
+// Values (and each of their files) have to fit in a smoosh chunk too.
+fn file_size_limit(chunk_size: usize) -> usize {
+    FILE_SIZE_LIMIT.min(chunk_size)
+}
+
+impl GenericIndexed {
+    fn new<I: Iterator<Item = usize>>(name: &str, sizes: I, reverse_lookup: bool, limit: usize) -> Result<Self, String> {
+        let mut ends = Vec::with_capacity(sizes.size_hint().0);
+        let mut end = 0;
+        for size in sizes {
+            end += size;
+            ends.push(end);
+        }
+        let mut indexed = GenericIndexed{name: name.to_string(), ends, reverse_lookup, power: None};
+        if indexed.size() > limit {
+            indexed.split(limit)?;
+        }
+        Ok(indexed)
+    }
+
+    // Moves values out to files of their own (version 2), if not already.
+    fn split(&mut self, limit: usize) -> Result<(), String> {
+        if self.power.is_none() {
+            self.power = Some(self.power_per_file(limit)?);
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> usize {
+        self.ends.len() * 4 + self.ends.last().cloned().unwrap_or(0) + 4 // + Integer.BYTES
+    }
+
+    // Largest power of 2 values per file that keeps every file within limits.
+    fn power_per_file(&self, limit: usize) -> Result<usize, String> {
+        let fits = |per_file: usize| {
+            let mut start = 0;
+            self.ends.chunks(per_file).all(|file_ends| {
+                let end = file_ends[file_ends.len() - 1];
+                let size = end - start;
+                start = end;
+                size <= limit
+            })
+        };
+        (0..31).rev().find(|power| fits(1 << power))
+            .ok_or(format!("`{}` has a single value over {} bytes", self.name, limit))
+    }
+
+    fn write(&self, writer: &mut dyn Write, value: &dyn Fn(usize, &mut dyn Write)) {
+        match self.power {
+            None => {
+                writer.write_u8(1).unwrap(); // VERSION_ONE
+                writer.write_u8(self.reverse_lookup as u8).unwrap();
+                writer.write_u32::<BE>(self.size() as u32).unwrap();
+                writer.write_u32::<BE>(self.ends.len() as u32).unwrap(); // numWritten
+                for end in &self.ends {
+                    writer.write_u32::<BE>(*end as u32).unwrap();
+                }
+                for i in 0..self.ends.len() {
+                    value(i, writer);
+                }
+            },
+            Some(power) => {
+                writer.write_u8(2).unwrap(); // VERSION_TWO
+                writer.write_u8(self.reverse_lookup as u8).unwrap();
+                writer.write_u32::<BE>(power as u32).unwrap(); // logBaseTwoOfElementsPerValueFile
+                writer.write_u32::<BE>(self.ends.len() as u32).unwrap(); // numElements
+                writer.write_u32::<BE>(self.name.len() as u32).unwrap();
+                writer.write_all(self.name.as_bytes()).unwrap();
+            },
+        }
+    }
+
+    fn write_files(&self, smoosher: &mut Smoosher, value: &dyn Fn(usize, &mut dyn Write)) -> Result<(), String> {
+        let power = match self.power {
+            Some(power) => power,
+            None => return Ok(()),
+        };
+        int_size(self.ends.len() * 4, &self.name);
+
+        let per_file = 1 << power;
+        for n in 0..(self.ends.len() + per_file - 1) / per_file {
+            smoosher.add_with(&format!("{}_value_{}", self.name, n), |writer| {
+                for i in n * per_file..self.ends.len().min((n + 1) * per_file) {
+                    value(i, writer);
+                }
+            })?;
+        }
+        smoosher.add_with(&format!("{}_header", self.name), |writer| {
+            let mut start = 0;
+            for file_ends in self.ends.chunks(per_file) {
+                for e in file_ends {
+                    writer.write_u32::<BE>((e - start) as u32).unwrap();
+                }
+                start = file_ends[file_ends.len() - 1];
+            }
+        })
+    }
+}
+
+// FrontCodedIndexed (V0), every value in a bucket stores only what differs
+// from the bucket's first value. Null (if any) is the first key and is only flagged.
+fn write_front_coded(writer: &mut dyn Write, keys: &IndexSet<Option<String>>, bucket_size: u8) {
+    let has_null = keys.get_index(0) == Some(&None);
+    let values: Vec<&[u8]> = keys.iter().filter_map(|k| k.as_ref().map(|k| k.as_bytes())).collect();
+
+    // Bucket sizes come first, so that the buckets can be written out right after.
+    let mut offsets = Vec::with_capacity(values.len() / bucket_size as usize * 4);
+    let mut size = 0;
+    for (n, bucket) in values.chunks(bucket_size as usize).enumerate() {
+        if n > 0 {
+            offsets.write_u32::<LE>(int_size(size, "front coded dictionary")).unwrap();
+        }
+        let first = bucket[0];
+        size += vbyte_size(first.len()) + first.len();
+        for value in &bucket[1..] {
+            let prefix = common_prefix(first, value);
+            size += vbyte_size(prefix) + vbyte_size(value.len() - prefix) + value.len() - prefix;
+        }
+    }
+
+    writer.write_u8(0x7f).unwrap(); // EncodedStringDictionaryWriter.VERSION
+    writer.write_u8(1).unwrap(); // StringEncodingStrategy.FRONT_CODED_ID
+    writer.write_u8(0).unwrap(); // FrontCodedIndexed.V0
+    writer.write_u8(bucket_size).unwrap();
+    writer.write_u8(has_null as u8).unwrap();
+    write_vbyte(writer, values.len());
+    write_vbyte(writer, int_size(offsets.len() + size, "front coded dictionary") as usize);
+    writer.write_all(&offsets).unwrap();
+    for bucket in values.chunks(bucket_size as usize) {
+        let first = bucket[0];
+        write_vbyte(writer, first.len());
+        writer.write_all(first).unwrap();
+        for value in &bucket[1..] {
+            let prefix = common_prefix(first, value);
+            write_vbyte(writer, prefix);
+            write_vbyte(writer, value.len() - prefix);
+            writer.write_all(&value[prefix..]).unwrap();
+        }
+    }
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
+}
+
+// VByte: 7 bits at a time, least significant first, last byte has the high bit set.
+fn write_vbyte(writer: &mut dyn Write, mut value: usize) {
+    while value >= 0x80 {
+        writer.write_u8((value & 0x7f) as u8).unwrap();
+        value >>= 7;
+    }
+    writer.write_u8(value as u8 | 0x80).unwrap();
+}
+
+fn vbyte_size(mut value: usize) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+// CompressedVSizeColumnarIntsSupplier
+fn write_vsize_compressed<W: Write + Seek>(writer: &mut W, indexes: &[usize], vint: &VInt, compression: conf::Compression) {
+    let chunk_factor = vint.chunk_factor();
+
+    writer.write_u8(2).unwrap(); // VERSION
+    writer.write_u8(vint.size as u8).unwrap();
+    writer.write_u32::<BE>(indexes.len() as u32).unwrap();
+    writer.write_u32::<BE>(chunk_factor as u32).unwrap();
+    writer.write_u8(compression.id()).unwrap();
+    let blocks = indexes.chunks(chunk_factor).map(|chunk| {
+        let mut block = Vec::with_capacity(chunk.len() * vint.size);
+        for i in chunk {
+            vint.write_value(&mut block, *i);
+        }
+        block
+    });
+    write_compressed(writer, (indexes.len() + chunk_factor - 1) / chunk_factor, blocks, compression);
+}
+
+// CompressedColumnarIntsSupplier of row offsets, `0` followed by `ends`.
+fn write_offsets_compressed<W: Write + Seek>(writer: &mut W, ends: &[usize], compression: conf::Compression) {
+    let chunk_factor = 65536 / 4;
+    let length = ends.len() + 1;
+
+    writer.write_u8(2).unwrap(); // VERSION
+    writer.write_u32::<BE>(length as u32).unwrap();
+    writer.write_u32::<BE>(chunk_factor as u32).unwrap();
+    writer.write_u8(compression.id()).unwrap();
+    let num_blocks = (length + chunk_factor - 1) / chunk_factor;
+    let blocks = (0..num_blocks).map(|n| {
+        let rows = n * chunk_factor..length.min((n + 1) * chunk_factor);
+        let mut block = Vec::with_capacity(rows.len() * 4);
+        for row in rows {
+            let offset = if row == 0 { 0 } else { ends[row - 1] };
+            block.write_u32::<LE>(int_size(offset, "row offsets")).unwrap();
+        }
+        block
+    });
+    write_compressed(writer, num_blocks, blocks, compression);
+}
+
 struct VInt {
     size: usize,
+    // Uncompressed columns are read as big endian, compressed blocks as little endian.
+    big_endian: bool,
 }
 
 impl VInt {
-    fn new(len: usize) -> Self {
+    fn new(len: usize, compression: conf::Compression) -> Self {
         Self{
             size: ((len as f64).log2() / 8. + 1.) as usize,
+            big_endian: compression == conf::Compression::None,
         }
     }
 
-    fn write_value(&self, out: &mut Write, val: usize) {
+    // Largest power of 2 values that fit in a 64KiB block (with padding
+    // needed for reading 3-byte values as ints).
+    fn chunk_factor(&self) -> usize {
+        let padding = if self.size > 2 { 4 - self.size } else { 0 };
+        let max = (65536 - padding) / self.size;
+        1 << (63 - (max as u64).leading_zeros())
+    }
+
+    fn write_value(&self, out: &mut dyn Write, val: usize) {
         match self.size {
             1 => out.write_u8(val as u8).unwrap(),
-            2 => match conf::vals.compression {
-                conf::Compression::None => out.write_u16::<BE>(val as u16).unwrap(),
-                conf::Compression::LZ4 => out.write_u16::<LE>(val as u16).unwrap(),
-            },
-            3 => match conf::vals.compression {
-                conf::Compression::None => out.write_u24::<BE>(val as u32).unwrap(),
-                conf::Compression::LZ4 => out.write_u24::<LE>(val as u32).unwrap(),
-            },
-            4 => match conf::vals.compression {
-                conf::Compression::None => out.write_u32::<BE>(val as u32).unwrap(),
-                conf::Compression::LZ4 => out.write_u32::<LE>(val as u32).unwrap(),
-            },
+            2 if self.big_endian => out.write_u16::<BE>(val as u16).unwrap(),
+            2 => out.write_u16::<LE>(val as u16).unwrap(),
+            3 if self.big_endian => out.write_u24::<BE>(val as u32).unwrap(),
+            3 => out.write_u24::<LE>(val as u32).unwrap(),
+            4 if self.big_endian => out.write_u32::<BE>(val as u32).unwrap(),
+            4 => out.write_u32::<LE>(val as u32).unwrap(),
             _ => (),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ByteOrder;
+
+    #[test]
+    fn generic_indexed_v2_header_is_laid_out_as_druid_reads_it() {
+        let indexed = GenericIndexed{name: "dim".to_string(), ends: vec![4, 9, 13], reverse_lookup: true, power: Some(1)};
+        let mut header = vec![];
+        indexed.write(&mut header, &|_, _| unreachable!());
+        // GenericIndexedWriter.MULTI_FILE_META_SERDE_HELPER
+        assert_eq!(header, [
+            0x02, // VERSION_TWO
+            0x01, // reverse lookup
+            0x00, 0x00, 0x00, 0x01, // logBaseTwoOfElementsPerValueFile
+            0x00, 0x00, 0x00, 0x03, // numElements
+            0x00, 0x00, 0x00, 0x03, b'd', b'i', b'm', // name length, name
+        ]);
+    }
+
+    #[test]
+    fn generic_indexed_files_fit_in_the_limit() {
+        let indexed = GenericIndexed{name: "dim".to_string(), ends: vec![4, 9, 13, 20], reverse_lookup: false, power: None};
+        assert_eq!(indexed.power_per_file(20), Ok(30));
+        assert_eq!(indexed.power_per_file(19), Ok(1));
+        assert_eq!(indexed.power_per_file(10), Ok(0));
+        assert!(indexed.power_per_file(6).is_err());
+    }
+
+    fn keys(values: &[Option<&str>]) -> IndexSet<Option<String>> {
+        values.iter().map(|v| v.map(|v| v.to_string())).collect()
+    }
+
+    fn read_vbyte(bytes: &mut &[u8]) -> usize {
+        let mut value = 0;
+        for shift in (0..).step_by(7) {
+            let b = bytes[0];
+            *bytes = &bytes[1..];
+            value |= ((b & 0x7f) as usize) << shift;
+            if b & 0x80 != 0 {
+                break;
+            }
+        }
+        value
+    }
+
+    // Reads a front coded dictionary the way FrontCodedIndexed does, bucket by bucket
+    // from the offsets, returning the bucket size, the null flag and the values.
+    fn read_front_coded(mut bytes: &[u8]) -> (u8, bool, Vec<Vec<u8>>) {
+        assert_eq!(&bytes[..3], [0x7f, 1, 0]);
+        let (bucket_size, has_null) = (bytes[3], bytes[4] == 1);
+        bytes = &bytes[5..];
+        let count = read_vbyte(&mut bytes);
+        let size = read_vbyte(&mut bytes);
+        assert_eq!(bytes.len(), size);
+        let buckets = (count + bucket_size as usize - 1) / bucket_size as usize;
+        let offsets: Vec<usize> = (1..buckets).map(|b| LE::read_u32(&bytes[(b - 1) * 4..]) as usize).collect();
+        let data = &bytes[buckets.saturating_sub(1) * 4..];
+
+        let mut values = vec![];
+        for b in 0..buckets {
+            let mut bucket = &data[if b == 0 { 0 } else { offsets[b - 1] }..];
+            let len = read_vbyte(&mut bucket);
+            let first = bucket[..len].to_vec();
+            bucket = &bucket[len..];
+            values.push(first.clone());
+            for _ in 1..(count - b * bucket_size as usize).min(bucket_size as usize) {
+                let prefix = read_vbyte(&mut bucket);
+                let len = read_vbyte(&mut bucket);
+                let mut value = first[..prefix].to_vec();
+                value.extend_from_slice(&bucket[..len]);
+                bucket = &bucket[len..];
+                values.push(value);
+            }
+            if b + 1 == buckets {
+                assert!(bucket.is_empty(), "{} bytes after the last bucket", bucket.len());
+            } else {
+                assert_eq!(data.len() - bucket.len(), offsets[b]);
+            }
+        }
+        (bucket_size, has_null, values)
+    }
+
+    #[test]
+    fn vbytes_are_least_significant_first() {
+        for (value, bytes) in &[(0, vec![0x80]), (127, vec![0xff]), (128, vec![0x00, 0x81]), (16_384, vec![0x00, 0x00, 0x81])] {
+            let mut written = vec![];
+            write_vbyte(&mut written, *value);
+            assert_eq!(&written, bytes);
+            assert_eq!(vbyte_size(*value), bytes.len());
+            assert_eq!(read_vbyte(&mut &written[..]), *value);
+        }
+    }
+
+    #[test]
+    fn front_coded_dictionary_is_laid_out_as_druid_reads_it() {
+        let mut written = vec![];
+        write_front_coded(&mut written, &keys(&[None, Some("apple"), Some("apply"), Some("banana")]), 2);
+        let mut expected = vec![0x7f, 1, 0, 2, 1, 0x83, 0x94, 9, 0, 0, 0, 0x85];
+        expected.extend_from_slice(b"apple");
+        expected.extend_from_slice(&[0x84, 0x81, b'y', 0x86]);
+        expected.extend_from_slice(b"banana");
+        assert_eq!(written, expected);
+    }
+
+    #[test]
+    fn front_coded_dictionaries_read_back() {
+        let values: Vec<String> = (0..37).map(|n| format!("page/{}/{}", n / 10, n)).collect();
+        for bucket_size in &[1, 4, 16] {
+            // 37 values leave the last bucket partial for all of these.
+            for null in &[false, true] {
+                let mut dictionary: Vec<Option<&str>> = values.iter().map(|v| Some(v.as_str())).collect();
+                if *null {
+                    dictionary.insert(0, None);
+                }
+                let mut written = vec![];
+                write_front_coded(&mut written, &keys(&dictionary), *bucket_size);
+                let (size, has_null, read) = read_front_coded(&written);
+                assert_eq!((size, has_null), (*bucket_size, *null));
+                assert_eq!(read, values.iter().map(|v| v.as_bytes().to_vec()).collect::<Vec<_>>());
+            }
+        }
+
+        for dictionary in &[vec![], vec![None]] {
+            let mut written = vec![];
+            write_front_coded(&mut written, &keys(dictionary), 4);
+            assert_eq!(written, [0x7f, 1, 0, 4, dictionary.len() as u8, 0x80, 0x80]);
+            assert_eq!(read_front_coded(&written), (4, !dictionary.is_empty(), vec![]));
+        }
+    }
+}
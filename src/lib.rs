@@ -1,63 +1,120 @@
 extern crate byteorder;
 #[macro_use]
 extern crate clap;
+extern crate concise;
+extern crate flate2;
 #[macro_use] extern crate lazy_static;
 extern crate indexmap;
 #[macro_use] extern crate log;
 extern crate lz4;
 extern crate num_cpus;
+extern crate roaring;
 #[macro_use]
 extern crate serde_json;
 extern crate structopt;
+extern crate zstd;
 
-use byteorder::{BE, LE, WriteBytesExt};
-use indexmap::IndexSet;
+use byteorder::{BE, LE, ReadBytesExt, WriteBytesExt};
 use serde_json::Value;
 
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fs;
-use std::io::{BufWriter, Write};
-use std::path::PathBuf;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
 use std::time::Instant;
 
+pub mod avro;
+mod bitmap;
+pub mod compression;
 pub mod conf;
+pub mod granularity;
 mod interner;
+mod longs;
+mod lzf;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+pub mod segment;
+mod smoosh;
+mod spec;
+pub mod spill;
+pub mod timestamp;
 mod zip;
+use bitmap::Bitmap;
 use interner::IS;
-use zip::Zip;
+use smoosh::Smoosher;
+use zip::{Zip, ZipError};
+
+/// Column holding parsed timestamps, whatever the input column was.
+pub const TIME_COLUMN: &str = "__time";
 
 lazy_static! {
     static ref META_TYPES: HashMap<&'static str, String> = {
+        let (part_long, part_float, part_double) = match conf::vals.null_handling {
+            conf::NullHandling::Default => (
+                json!({"type": "long", "byteOrder": "LITTLE_ENDIAN"}),
+                json!({"type": "float", "byteOrder": "LITTLE_ENDIAN"}),
+                json!({"type": "double", "byteOrder": "LITTLE_ENDIAN"}),
+            ),
+            conf::NullHandling::SQL => (
+                json!({
+                    "type": "longV2",
+                    "byteOrder": "LITTLE_ENDIAN",
+                    "bitmapSerdeFactory": conf::vals.bitmap.to_json(),
+                }),
+                json!({
+                    "type": "floatV2",
+                    "byteOrder": "LITTLE_ENDIAN",
+                    "bitmapSerdeFactory": conf::vals.bitmap.to_json(),
+                }),
+                json!({
+                    "type": "doubleV2",
+                    "byteOrder": "LITTLE_ENDIAN",
+                    "bitmapSerdeFactory": conf::vals.bitmap.to_json(),
+                }),
+            ),
+        };
         let meta_long = json!({
             "valueType": "LONG",
             "hasMultipleValues": false,
-            "parts": [{
-                "type": "long",
-                "byteOrder": "LITTLE_ENDIAN",
-            }],
+            "parts": [part_long],
+        });
+        let meta_float = json!({
+            "valueType": "FLOAT",
+            "hasMultipleValues": false,
+            "parts": [part_float],
         });
         let meta_double = json!({
             "valueType": "DOUBLE",
             "hasMultipleValues": false,
+            "parts": [part_double],
+        });
+        let meta_string = json!({
+            "valueType": "STRING",
+            "hasMultipleValues": false,
             "parts": [{
-                "type": "double",
+                "type": "stringDictionary",
+                "bitmapSerdeFactory": conf::vals.bitmap.to_json(),
                 "byteOrder": "LITTLE_ENDIAN",
             }],
         });
-        let meta_string = json!({
+        let meta_multi_string = json!({
             "valueType": "STRING",
-            "hasMultipleValues": false,
+            "hasMultipleValues": true,
             "parts": [{
                 "type": "stringDictionary",
-                "bitmapSerdeFactory": {"type": "concise"},
+                "bitmapSerdeFactory": conf::vals.bitmap.to_json(),
                 "byteOrder": "LITTLE_ENDIAN",
             }],
         });
 
         let mut meta_types = HashMap::new();
         meta_types.insert("long", meta_long.to_string());
+        meta_types.insert("float", meta_float.to_string());
         meta_types.insert("double", meta_double.to_string());
         meta_types.insert("string", meta_string.to_string());
+        meta_types.insert("multi_string", meta_multi_string.to_string());
         meta_types
     };
 }
@@ -65,332 +122,1473 @@ lazy_static! {
 #[derive(Debug)]
 enum ValVec {
     IndexedString(IS),
-    Integer(Vec<i64>),
-    Float(Vec<f64>),
+    MultiIndexedString(IS),
+    // Numeric values come with per-row "is null" flags.
+    Integer(Vec<i64>, Vec<bool>),
+    Float(Vec<f64>, Vec<bool>),
+    Float32(Vec<f32>, Vec<bool>),
 }
 
 trait VVWrite {
-    fn write(&self, writer: &mut Write);
+    fn write(&self, writer: &mut dyn Write);
 }
 
 impl VVWrite for i64 {
-    fn write(&self, writer: &mut Write) {
+    fn write(&self, writer: &mut dyn Write) {
         writer.write_i64::<LE>(*self).unwrap();
     }
 }
 
+impl VVWrite for f32 {
+    fn write(&self, writer: &mut dyn Write) {
+        writer.write_f32::<LE>(*self).unwrap();
+    }
+}
+
 impl VVWrite for f64 {
-    fn write(&self, writer: &mut Write) {
+    fn write(&self, writer: &mut dyn Write) {
         writer.write_f64::<LE>(*self).unwrap();
     }
 }
 
-fn compress(out: &mut Write, data: &[u8]) {
+fn aggregate<T>(values: &[T], nulls: &[bool], starts: &[usize], aggregator: conf::Aggregator) -> (Vec<T>, Vec<bool>)
+where T: Copy + Default + PartialOrd + std::ops::Add<Output = T> {
+    let skip_nulls = match conf::vals.null_handling {
+        conf::NullHandling::Default => false,
+        conf::NullHandling::SQL => true,
+    };
+
+    let mut new_values = Vec::with_capacity(starts.len());
+    let mut new_nulls = Vec::with_capacity(starts.len());
+    for (group, start) in starts.iter().enumerate() {
+        let end = starts.get(group + 1).cloned().unwrap_or(values.len());
+        let mut acc = None;
+        for row in *start..end {
+            if skip_nulls && nulls[row] {
+                continue;
+            }
+            let v = values[row];
+            acc = Some(match (acc, aggregator) {
+                (None, _) => v,
+                (Some(a), conf::Aggregator::LongMin) |
+                (Some(a), conf::Aggregator::DoubleMin) |
+                (Some(a), conf::Aggregator::FloatMin) => if v < a { v } else { a },
+                (Some(a), conf::Aggregator::LongMax) |
+                (Some(a), conf::Aggregator::DoubleMax) |
+                (Some(a), conf::Aggregator::FloatMax) => if v > a { v } else { a },
+                (Some(a), _) => a + v,
+            });
+        }
+        new_values.push(acc.unwrap_or_default());
+        new_nulls.push(acc.is_none());
+    }
+    (new_values, new_nulls)
+}
+
+/// Sizes and offsets are read back as Java ints, anything larger can not be written.
+fn int_size(size: usize, what: &str) -> u32 {
+    if size > i32::MAX as usize {
+        panic!("`{}` needs {} bytes, over the 2GiB Druid can address", what, size);
+    }
+    size as u32
+}
+
+fn compress(out: &mut dyn Write, data: &[u8], compression: conf::Compression) {
     out.write_u32::<BE>(0).unwrap(); // "nullness marker"
-    match conf::vals.compression {
-        conf::Compression::None => out.write(&data).unwrap(),
-        conf::Compression::LZ4 => out.write(&lz4::block::compress(
-            &data,
+    match compression {
+        conf::Compression::None | conf::Compression::Uncompressed => out.write_all(data).unwrap(),
+        conf::Compression::LZF => {
+            let mut compressed = Vec::with_capacity(data.len());
+            lzf::compress(&mut compressed, data);
+            out.write_all(&compressed).unwrap();
+        },
+        conf::Compression::LZ4 => out.write_all(&lz4::block::compress(
+            data,
             Some(lz4::block::CompressionMode::HIGHCOMPRESSION(9)),
             false,
         ).unwrap()).unwrap(),
+        // Same level as Druid's `ZstdCompressor`
+        conf::Compression::ZSTD => out.write_all(&zstd::bulk::compress(data, 3).unwrap()).unwrap(),
     };
 }
 
-fn write_numeric<T: VVWrite>(writer: &mut Write, meta: &str, data: &[T]) {
+fn position<W: Seek>(writer: &mut W) -> usize {
+    writer.stream_position().unwrap() as usize
+}
+
+/// Overwrites what was written at `pos` (a placeholder), going back to the end afterwards.
+fn patch<W: Write + Seek>(writer: &mut W, pos: usize, data: &[u8]) {
+    let end = writer.stream_position().unwrap();
+    writer.seek(SeekFrom::Start(pos as u64)).unwrap();
+    writer.write_all(data).unwrap();
+    writer.seek(SeekFrom::Start(end)).unwrap();
+}
+
+/// GenericIndexed (v1) of compressed `blocks`. Blocks are compressed and written
+/// one by one, so their offsets and the total size get filled in at the end.
+fn write_compressed<W: Write + Seek, I: Iterator<Item = Vec<u8>>>(
+    writer: &mut W,
+    num_blocks: usize,
+    blocks: I,
+    compression: conf::Compression,
+) {
+    writer.write_u8(1).unwrap(); // VERSION
+    writer.write_u8(0).unwrap(); // REVERSE_LOOKUP_DISALLOWED
+    let size_pos = position(writer);
+    writer.write_u32::<BE>(0).unwrap();
+    writer.write_u32::<BE>(num_blocks as u32).unwrap(); // numWritten
+    let header_pos = position(writer);
+    writer.write_all(&vec![0; num_blocks * 4]).unwrap();
+
+    let mut header = Vec::with_capacity(num_blocks * 4);
+    let mut compressed = Vec::with_capacity(0x10000);
+    let mut values_len = 0;
+    for block in blocks {
+        compressed.clear();
+        compress(&mut compressed, &block, compression);
+        writer.write_all(&compressed).unwrap();
+        values_len += compressed.len();
+        header.write_u32::<BE>(int_size(values_len, "compressed blocks")).unwrap();
+    }
+
+    let mut size = vec![];
+    size.write_u32::<BE>(int_size(header.len() + values_len + 4, "compressed blocks")).unwrap(); // + Integer.NUM_BYTES
+    patch(writer, size_pos, &size);
+    patch(writer, header_pos, &header);
+}
+
+fn write_meta(writer: &mut dyn Write, meta: &str) {
     writer.write_u32::<BE>(meta.len() as u32).unwrap();
     writer.write_all(meta.as_bytes()).unwrap();
+}
 
-    writer.write_u8(2).unwrap(); // VERSION
+fn write_numeric<W: Write + Seek, T: VVWrite>(writer: &mut W, meta: &str, data: &[T], nulls: &[bool], compression: conf::Compression) {
+    write_meta(writer, meta);
+    let size_per = 0x10000 / std::mem::size_of::<T>(); // values per 64KiB block
+    let blocks = data.chunks(size_per).map(|chunk| {
+        let mut block = Vec::with_capacity(0x10000);
+        for v in chunk {
+            v.write(&mut block);
+        }
+        block
+    });
+    write_blocks(writer, data.len(), size_per, None, blocks, nulls, compression);
+}
+
+// Longs go through `CompressionFactory`'s delta/table encodings with `--long-encoding auto`.
+fn write_longs<W: Write + Seek>(writer: &mut W, meta: &str, data: &[i64], nulls: &[bool], compression: conf::Compression) {
+    let encoding = match conf::vals.long_encoding {
+        conf::LongEncoding::Longs => None,
+        conf::LongEncoding::Auto => longs::Encoding::analyse(data),
+    };
+    match encoding {
+        None => write_numeric(writer, meta, data, nulls, compression),
+        Some(encoding) => {
+            write_meta(writer, meta);
+            let size_per = encoding.size_per();
+            let blocks = data.chunks(size_per).map(|chunk| {
+                let mut block = Vec::with_capacity(0x10000);
+                encoding.write_block(&mut block, chunk);
+                block
+            });
+            write_blocks(writer, data.len(), size_per, Some(&encoding), blocks, nulls, compression);
+        },
+    }
+}
 
-    let length = data.len();
+fn write_blocks<W: Write + Seek, I: Iterator<Item = Vec<u8>>>(
+    writer: &mut W,
+    length: usize,
+    size_per: usize,
+    encoding: Option<&longs::Encoding>,
+    blocks: I,
+    nulls: &[bool],
+    compression: conf::Compression,
+) {
+    let sql = match conf::vals.null_handling {
+        conf::NullHandling::Default => false,
+        conf::NullHandling::SQL => true,
+    };
+    let start = position(writer);
+    if sql {
+        writer.write_u32::<BE>(0).unwrap(); // offset of the null bitmap, filled in below
+    }
 
-    writer.write_u32::<BE>(length as u32).unwrap(); // totalSize
+    // CompressionFactory.setEncodingFlag
+    let mut compression_id = compression.id();
+    if encoding.is_some() {
+        compression_id = (compression_id as i8).wrapping_sub(126) as u8;
+    }
 
-    let size_per = 8192;
+    writer.write_u8(2).unwrap(); // VERSION
+    writer.write_u32::<BE>(int_size(length, "rows")).unwrap(); // totalSize
     writer.write_u32::<BE>(size_per as u32).unwrap();
-    writer.write_u8(conf::vals.compression as u8).unwrap(); // compression
-    writer.write_u8(1).unwrap(); // VERSION
-    writer.write_u8(0).unwrap(); // REVERSE_LOOKUP_DISALLOWED
+    writer.write_u8(compression_id).unwrap(); // compression
+    if let Some(encoding) = encoding {
+        encoding.write_header(writer);
+    }
+    write_compressed(writer, (length + size_per - 1) / size_per, blocks, compression);
 
-    let mut header = Vec::with_capacity(data.len() / size_per * 4);
-    let mut values_all = Vec::with_capacity(data.len() * 8);
-    let mut values_batch = Vec::with_capacity(size_per);
+    if sql {
+        let mut offset = vec![];
+        offset.write_u32::<BE>(int_size(position(writer) - start - 4, "compressed blocks")).unwrap();
+        patch(writer, start, &offset);
 
-    for chunk in data.chunks(size_per) {
-        for v in chunk {
-            v.write(&mut values_batch);
+        let mut bitmap = Bitmap::new();
+        for (row, _) in nulls.iter().enumerate().filter(|(_, null)| **null) {
+            bitmap.append(row);
+        }
+        let size = bitmap.size();
+        if size > 0 {
+            writer.write_u32::<BE>(int_size(size, "null bitmap")).unwrap();
+            bitmap.write(writer);
+        }
+    }
+}
+
+fn persist_nulls(writer: &mut dyn Write, nulls: &[bool]) {
+    writer.write_u64::<LE>(nulls.len() as u64).unwrap();
+    for null in nulls {
+        writer.write_u8(*null as u8).unwrap();
+    }
+}
+
+fn load_nulls(reader: &mut dyn Read) -> Vec<bool> {
+    let len = reader.read_u64::<LE>().unwrap() as usize;
+    (0..len).map(|_| reader.read_u8().unwrap() != 0).collect()
+}
+
+// Compares rows by `columns`, in order.
+fn cmp_rows(columns: &[&ValVec], a: usize, b: usize) -> Ordering {
+    columns.iter().fold(Ordering::Equal, |o, c| o.then_with(|| c.cmp_rows(a, b)))
+}
+
+// Merges two sorted lists of rows, taking from `a` first on ties.
+fn merge_rows(columns: &[&ValVec], a: &[usize], b: &[usize]) -> Vec<usize> {
+    let mut merged = Vec::with_capacity(a.len() + b.len());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if cmp_rows(columns, b[j], a[i]) == Ordering::Less {
+            merged.push(b[j]);
+            j += 1;
+        } else {
+            merged.push(a[i]);
+            i += 1;
         }
-        compress(&mut values_all, &values_batch);
-        values_batch.clear();
-        header.write_u32::<BE>(values_all.len() as u32).unwrap();
     }
+    merged.extend_from_slice(&a[i..]);
+    merged.extend_from_slice(&b[j..]);
+    merged
+}
 
-    writer.write_u32::<BE>((header.len() + values_all.len() + 4) as u32).unwrap(); // + Integer.NUM_BYTES
-    writer.write_u32::<BE>((length as f64 / size_per as f64).ceil() as u32).unwrap(); // numWritten
-    writer.write_all(&header).unwrap();
-    writer.write_all(&values_all).unwrap();
+// Strings, numbers and booleans as strings, `None` for anything else.
+fn scalar_string(value: Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
 }
 
 impl ValVec {
     fn push_s(&mut self, value: String) {
-        if let ValVec::IndexedString(is) = self { is.add_s(value) }
+        self.make_strings();
+        if let ValVec::IndexedString(is) | ValVec::MultiIndexedString(is) = self { is.add_s(value) }
+    }
+
+    fn push_ms(&mut self, values: Vec<String>) {
+        self.make_strings();
+        self.make_multi();
+        if let ValVec::MultiIndexedString(is) = self { is.add_ms(values) }
     }
 
     fn push_i(&mut self, value: i64) {
-        if let ValVec::Integer(i) = self { i.push(value) }
+        match self {
+            ValVec::Integer(i, n) => {
+                i.push(value);
+                n.push(false);
+            },
+            ValVec::Float(_, _) | ValVec::Float32(_, _) => self.push_f(value as f64),
+            _ => self.push_s(value.to_string()),
+        }
     }
 
     fn push_f(&mut self, value: f64) {
-        if let ValVec::Float(f) = self { f.push(value) }
+        self.make_float();
+        match self {
+            ValVec::Float(f, n) => {
+                f.push(value);
+                n.push(false);
+            },
+            ValVec::Float32(f, n) => {
+                f.push(value as f32);
+                n.push(false);
+            },
+            _ => self.push_s(value.to_string()),
+        }
+    }
+
+    fn push_null(&mut self) {
+        match self {
+            ValVec::IndexedString(is) | ValVec::MultiIndexedString(is) => is.add_null(),
+            ValVec::Integer(i, n) => {
+                i.push(0);
+                n.push(true);
+            },
+            ValVec::Float(f, n) => {
+                f.push(0.);
+                n.push(true);
+            },
+            ValVec::Float32(f, n) => {
+                f.push(0.);
+                n.push(true);
+            },
+        }
+    }
+
+    // Coerces `value` to `kind`, see `Data::add_typed`.
+    fn push_typed(&mut self, value: Value, kind: conf::DimensionType) -> bool {
+        match (kind, value) {
+            (_, Value::Null) => self.push_null(),
+            (conf::DimensionType::String, Value::Array(a)) => {
+                match a.into_iter().map(scalar_string).collect() {
+                    Some(values) => self.push_ms(values),
+                    None => return self.failed(),
+                }
+            },
+            (conf::DimensionType::String, value) => match scalar_string(value) {
+                Some(s) => self.push_s(s),
+                None => return self.failed(),
+            },
+            (conf::DimensionType::Long, value) => match value {
+                Value::Number(ref n) if n.is_i64() => self.push_i(n.as_i64().unwrap()),
+                Value::Number(ref n) => self.push_i(n.as_f64().unwrap() as i64),
+                Value::String(ref s) => match (s.trim().parse(), s.trim().parse::<f64>()) {
+                    (Ok(i), _) => self.push_i(i),
+                    (_, Ok(f)) => self.push_i(f as i64),
+                    (_, _) => return self.failed(),
+                },
+                _ => return self.failed(),
+            },
+            (_, value) => match value {
+                Value::Number(ref n) => self.push_f(n.as_f64().unwrap()),
+                Value::String(ref s) => match s.trim().parse() {
+                    Ok(f) => self.push_f(f),
+                    Err(_) => return self.failed(),
+                },
+                _ => return self.failed(),
+            },
+        }
+        true
+    }
+
+    // Stores a null in place of a value that could not be coerced.
+    fn failed(&mut self) -> bool {
+        self.push_null();
+        false
+    }
+
+    fn pad(&mut self, rows: usize) {
+        for _ in self.len()..rows {
+            self.push_null();
+        }
+    }
+
+    fn padded(mut self, rows: usize) -> Self {
+        self.pad(rows);
+        self
+    }
+
+    fn typed(kind: conf::DimensionType) -> Self {
+        match kind {
+            conf::DimensionType::String => ValVec::IndexedString(IS::new()),
+            conf::DimensionType::Long => ValVec::Integer(Vec::new(), Vec::new()),
+            conf::DimensionType::Float => ValVec::Float32(Vec::new(), Vec::new()),
+            conf::DimensionType::Double => ValVec::Float(Vec::new(), Vec::new()),
+        }
+    }
+
+    fn empty(&self) -> Self {
+        match self {
+            ValVec::IndexedString(_) => ValVec::IndexedString(IS::new()),
+            ValVec::MultiIndexedString(_) => ValVec::MultiIndexedString(IS::new_multi()),
+            ValVec::Integer(_, _) => ValVec::Integer(Vec::new(), Vec::new()),
+            ValVec::Float(_, _) => ValVec::Float(Vec::new(), Vec::new()),
+            ValVec::Float32(_, _) => ValVec::Float32(Vec::new(), Vec::new()),
+        }
+    }
+
+    fn make_multi(&mut self) {
+        if let ValVec::IndexedString(_) = self {
+            if let ValVec::IndexedString(mut is) = std::mem::replace(self, ValVec::Integer(vec![], vec![])) {
+                is.make_multi();
+                *self = ValVec::MultiIndexedString(is);
+            }
+        }
+    }
+
+    /// Promotes `Integer` to `Float`, strings and floats are left as they are.
+    fn make_float(&mut self) {
+        if let ValVec::Integer(i, n) = self {
+            let f = i.iter().map(|v| *v as f64).collect();
+            *self = ValVec::Float(f, std::mem::take(n));
+        }
+    }
+
+    /// Promotes `Integer` and `Float32` to `Float`.
+    fn make_double(&mut self) {
+        self.make_float();
+        if let ValVec::Float32(f, n) = self {
+            let f = f.iter().map(|v| *v as f64).collect();
+            *self = ValVec::Float(f, std::mem::take(n));
+        }
+    }
+
+    /// Turns numbers into strings (nulls stay nulls).
+    fn make_strings(&mut self) {
+        let (values, nulls): (Vec<String>, &Vec<bool>) = match self {
+            ValVec::Integer(i, n) => (i.iter().map(|v| v.to_string()).collect(), n),
+            ValVec::Float(f, n) => (f.iter().map(|v| v.to_string()).collect(), n),
+            ValVec::Float32(f, n) => (f.iter().map(|v| v.to_string()).collect(), n),
+            _ => return,
+        };
+        let mut is = IS::new();
+        for (value, null) in values.into_iter().zip(nulls) {
+            if *null {
+                is.add_null();
+            } else {
+                is.add_s(value);
+            }
+        }
+        *self = ValVec::IndexedString(is);
+    }
+
+    fn is_numeric(&self) -> bool {
+        matches!(self, ValVec::Integer(_, _) | ValVec::Float(_, _) | ValVec::Float32(_, _))
     }
 
     fn append(&mut self, other: &mut ValVec) {
+        // Workers may have inferred different types for the same column.
+        match (self.is_numeric(), other.is_numeric()) {
+            (true, false) => self.make_strings(),
+            (false, true) => other.make_strings(),
+            (true, true) => if std::mem::discriminant(self) != std::mem::discriminant(other) {
+                self.make_double();
+                other.make_double();
+            },
+            (false, false) => (),
+        }
+        if let (ValVec::MultiIndexedString(_), ValVec::IndexedString(_)) |
+               (ValVec::IndexedString(_), ValVec::MultiIndexedString(_)) = (&*self, &*other) {
+            self.make_multi();
+            other.make_multi();
+        }
         match (self, other) {
             (ValVec::IndexedString(is), ValVec::IndexedString(o)) => is.append(o),
-            (ValVec::Integer(i), ValVec::Integer(o)) => i.append(o),
-            (ValVec::Float(f), ValVec::Float(o)) => f.append(o),
+            (ValVec::MultiIndexedString(is), ValVec::MultiIndexedString(o)) => is.append(o),
+            (ValVec::Integer(i, n), ValVec::Integer(o, on)) => {
+                i.append(o);
+                n.append(on);
+            },
+            (ValVec::Float(f, n), ValVec::Float(o, on)) => {
+                f.append(o);
+                n.append(on);
+            },
+            (ValVec::Float32(f, n), ValVec::Float32(o, on)) => {
+                f.append(o);
+                n.append(on);
+            },
             (_, _) => unreachable!(),
         }
     }
 
+    fn sort(&mut self) {
+        match self {
+            ValVec::IndexedString(is) | ValVec::MultiIndexedString(is) => is.sort(),
+            _ => (),
+        }
+    }
+
+    fn permute(&mut self, rows: &[usize]) {
+        match self {
+            ValVec::IndexedString(is) | ValVec::MultiIndexedString(is) => is.permute(rows),
+            ValVec::Integer(i, n) => {
+                *i = rows.iter().map(|p| i[*p]).collect();
+                *n = rows.iter().map(|p| n[*p]).collect();
+            },
+            ValVec::Float(f, n) => {
+                *f = rows.iter().map(|p| f[*p]).collect();
+                *n = rows.iter().map(|p| n[*p]).collect();
+            },
+            ValVec::Float32(f, n) => {
+                *f = rows.iter().map(|p| f[*p]).collect();
+                *n = rows.iter().map(|p| n[*p]).collect();
+            },
+        }
+    }
+
+    // Nulls go first.
+    fn cmp_rows(&self, a: usize, b: usize) -> Ordering {
+        match self {
+            ValVec::IndexedString(is) | ValVec::MultiIndexedString(is) => is.cmp_rows(a, b),
+            ValVec::Integer(i, n) => n[b].cmp(&n[a]).then(i[a].cmp(&i[b])),
+            ValVec::Float(f, n) => n[b].cmp(&n[a])
+                .then(f[a].partial_cmp(&f[b]).unwrap_or(Ordering::Equal)),
+            ValVec::Float32(f, n) => n[b].cmp(&n[a])
+                .then(f[a].partial_cmp(&f[b]).unwrap_or(Ordering::Equal)),
+        }
+    }
+
+    // Compares row `a` with row `b` of `other`, a column of another Data,
+    // either both numeric or both strings. Nulls go first.
+    fn cmp_rows_with(&self, a: usize, other: &ValVec, b: usize) -> Ordering {
+        let number = |column: &ValVec, row: usize| match column {
+            ValVec::Integer(i, n) => (n[row], i[row] as f64),
+            ValVec::Float(f, n) => (n[row], f[row]),
+            ValVec::Float32(f, n) => (n[row], f[row] as f64),
+            _ => unreachable!(),
+        };
+        match (self, other) {
+            (ValVec::IndexedString(is), ValVec::IndexedString(o)) |
+            (ValVec::IndexedString(is), ValVec::MultiIndexedString(o)) |
+            (ValVec::MultiIndexedString(is), ValVec::IndexedString(o)) |
+            (ValVec::MultiIndexedString(is), ValVec::MultiIndexedString(o)) => is.row(a).cmp(o.row(b)),
+            (ValVec::Integer(i, n), ValVec::Integer(o, on)) => on[b].cmp(&n[a]).then(i[a].cmp(&o[b])),
+            (_, _) => {
+                let ((null_a, value_a), (null_b, value_b)) = (number(self, a), number(other, b));
+                null_b.cmp(&null_a).then(value_a.partial_cmp(&value_b).unwrap_or(Ordering::Equal))
+            },
+        }
+    }
+
+    fn is_null(&self, row: usize) -> bool {
+        match self {
+            ValVec::IndexedString(is) | ValVec::MultiIndexedString(is) => is.row(row).eq(std::iter::once(&None)),
+            ValVec::Integer(_, n) | ValVec::Float(_, n) | ValVec::Float32(_, n) => n[row],
+        }
+    }
+
+    fn aggregate(&mut self, starts: &[usize], aggregator: conf::Aggregator) {
+        match self {
+            ValVec::Integer(i, n) => {
+                let (new_i, new_n) = aggregate(i, n, starts, aggregator);
+                *i = new_i;
+                *n = new_n;
+            },
+            ValVec::Float(f, n) => {
+                let (new_f, new_n) = aggregate(f, n, starts, aggregator);
+                *f = new_f;
+                *n = new_n;
+            },
+            ValVec::Float32(f, n) => {
+                let (new_f, new_n) = aggregate(f, n, starts, aggregator);
+                *f = new_f;
+                *n = new_n;
+            },
+            _ => self.permute(starts),
+        }
+    }
+
+    fn select(&self, rows: Range<usize>) -> Self {
+        match self {
+            ValVec::IndexedString(is) => ValVec::IndexedString(is.select(rows)),
+            ValVec::MultiIndexedString(is) => ValVec::MultiIndexedString(is.select(rows)),
+            ValVec::Integer(i, n) => ValVec::Integer(i[rows.clone()].to_vec(), n[rows].to_vec()),
+            ValVec::Float(f, n) => ValVec::Float(f[rows.clone()].to_vec(), n[rows].to_vec()),
+            ValVec::Float32(f, n) => ValVec::Float32(f[rows.clone()].to_vec(), n[rows].to_vec()),
+        }
+    }
+
+    fn to_numeric(&self, kind: conf::DimensionType) -> Option<ValVec> {
+        let (values, n): (Vec<f64>, &Vec<bool>) = match self {
+            ValVec::Integer(i, n) if kind == conf::DimensionType::Long => {
+                return Some(ValVec::Integer(i.clone(), n.clone()));
+            },
+            ValVec::Integer(i, n) => (i.iter().map(|v| *v as f64).collect(), n),
+            ValVec::Float(f, n) => (f.clone(), n),
+            ValVec::Float32(f, n) => (f.iter().map(|v| *v as f64).collect(), n),
+            _ => return None,
+        };
+        Some(match kind {
+            conf::DimensionType::Long => ValVec::Integer(values.iter().map(|v| *v as i64).collect(), n.clone()),
+            conf::DimensionType::Float => ValVec::Float32(values.iter().map(|v| *v as f32).collect(), n.clone()),
+            _ => ValVec::Float(values, n.clone()),
+        })
+    }
+
+    fn kind(&self) -> conf::DimensionType {
+        match self {
+            ValVec::IndexedString(_) | ValVec::MultiIndexedString(_) => conf::DimensionType::String,
+            ValVec::Integer(_, _) => conf::DimensionType::Long,
+            ValVec::Float(_, _) => conf::DimensionType::Double,
+            ValVec::Float32(_, _) => conf::DimensionType::Float,
+        }
+    }
+
+    /// Writes the column in a compact form, read back with `load`. Has to be called after `sort`.
+    fn persist(&self, writer: &mut dyn Write) {
+        match self {
+            ValVec::IndexedString(is) => {
+                writer.write_u8(0).unwrap();
+                is.persist(writer);
+            },
+            ValVec::MultiIndexedString(is) => {
+                writer.write_u8(1).unwrap();
+                is.persist(writer);
+            },
+            ValVec::Integer(i, n) => {
+                writer.write_u8(2).unwrap();
+                persist_nulls(writer, n);
+                for v in i {
+                    writer.write_i64::<LE>(*v).unwrap();
+                }
+            },
+            ValVec::Float(f, n) => {
+                writer.write_u8(3).unwrap();
+                persist_nulls(writer, n);
+                for v in f {
+                    writer.write_f64::<LE>(*v).unwrap();
+                }
+            },
+            ValVec::Float32(f, n) => {
+                writer.write_u8(4).unwrap();
+                persist_nulls(writer, n);
+                for v in f {
+                    writer.write_f32::<LE>(*v).unwrap();
+                }
+            },
+        }
+    }
+
+    fn load(reader: &mut dyn Read) -> Self {
+        match reader.read_u8().unwrap() {
+            0 => ValVec::IndexedString(IS::load(reader)),
+            1 => ValVec::MultiIndexedString(IS::load(reader)),
+            2 => {
+                let n = load_nulls(reader);
+                let i = (0..n.len()).map(|_| reader.read_i64::<LE>().unwrap()).collect();
+                ValVec::Integer(i, n)
+            },
+            3 => {
+                let n = load_nulls(reader);
+                let f = (0..n.len()).map(|_| reader.read_f64::<LE>().unwrap()).collect();
+                ValVec::Float(f, n)
+            },
+            4 => {
+                let n = load_nulls(reader);
+                let f = (0..n.len()).map(|_| reader.read_f32::<LE>().unwrap()).collect();
+                ValVec::Float32(f, n)
+            },
+            kind => panic!("unknown persisted column kind {}", kind),
+        }
+    }
+
     fn len(&self) -> usize {
         match self {
-            ValVec::IndexedString(is) => is.len(),
-            ValVec::Integer(i) => i.len(),
-            ValVec::Float(f) => f.len(),
+            ValVec::IndexedString(is) | ValVec::MultiIndexedString(is) => is.len(),
+            ValVec::Integer(i, _) => i.len(),
+            ValVec::Float(f, _) => f.len(),
+            ValVec::Float32(f, _) => f.len(),
         }
     }
 }
 
+/// All columns are kept at the same length, rows missing a value get a null.
 #[derive(Debug, Default)]
-pub struct Data(HashMap<String, ValVec>);
+pub struct Data {
+    columns: HashMap<String, ValVec>,
+    rows: usize,
+}
 
 impl Data {
     pub fn new() -> Self {
-        Data(HashMap::new())
+        Data{columns: HashMap::new(), rows: 0}
     }
 
     pub fn add_s(&mut self, key: String, value: String) {
-        self.0.entry(key)
-            .or_insert_with(|| ValVec::IndexedString(IS::new()))
+        let rows = self.rows;
+        self.columns.entry(key)
+            .or_insert_with(|| ValVec::IndexedString(IS::new()).padded(rows))
             .push_s(value);
     }
 
+    pub fn add_ms(&mut self, key: String, values: Vec<String>) {
+        let rows = self.rows;
+        self.columns.entry(key)
+            .or_insert_with(|| ValVec::MultiIndexedString(IS::new_multi()).padded(rows))
+            .push_ms(values);
+    }
+
+    /// Adds the strings, numbers and booleans of `values` as a multi-value, returning
+    /// how many other elements (nulls, objects, nested arrays) were dropped.
+    pub fn add_array(&mut self, key: String, values: Vec<Value>) -> usize {
+        let count = values.len();
+        let strings: Vec<String> = values.into_iter().filter_map(scalar_string).collect();
+        let dropped = count - strings.len();
+        self.add_ms(key, strings);
+        dropped
+    }
+
     pub fn add_i(&mut self, key: String, value: i64) {
-        self.0.entry(key)
-            .or_insert_with(|| ValVec::Integer(Vec::new()))
+        let rows = self.rows;
+        self.columns.entry(key)
+            .or_insert_with(|| ValVec::Integer(Vec::new(), Vec::new()).padded(rows))
             .push_i(value);
     }
 
     pub fn add_f(&mut self, key: String, value: f64) {
-        self.0.entry(key)
-            .or_insert_with(|| ValVec::Float(Vec::new()))
+        let rows = self.rows;
+        self.columns.entry(key)
+            .or_insert_with(|| ValVec::Float(Vec::new(), Vec::new()).padded(rows))
             .push_f(value);
     }
 
+    /// Adds `value` coerced to `kind` (numbers into strings, numeric strings into numbers, ...).
+    /// Values that can not be coerced are stored as nulls and `false` is returned.
+    pub fn add_typed(&mut self, key: String, value: Value, kind: conf::DimensionType) -> bool {
+        let rows = self.rows;
+        self.columns.entry(key)
+            .or_insert_with(|| ValVec::typed(kind).padded(rows))
+            .push_typed(value, kind)
+    }
+
+    /// Finishes current row, filling all the columns it did not have with nulls.
+    pub fn next_row(&mut self) {
+        self.rows += 1;
+        for value in self.columns.values_mut() {
+            value.pad(self.rows);
+        }
+    }
+
+    /// Adds a whole column (of input read a column at a time). Columns shorter
+    /// than the others are padded with nulls, a longer one adds rows to them all.
+    #[cfg(any(test, feature = "parquet"))]
+    fn add_column(&mut self, key: String, column: ValVec) {
+        assert!(!self.columns.contains_key(&key), "column `{}` added twice", key);
+        self.rows = self.rows.max(column.len());
+        self.columns.insert(key, column);
+        for value in self.columns.values_mut() {
+            value.pad(self.rows);
+        }
+    }
+
     pub fn append(&mut self, other: Data) {
-        for (key, mut value) in other.0 {
-            self.0.entry(key)
-                .and_modify(|e| e.append(&mut value))
-                .or_insert(value);
+        let rows = self.rows;
+        for (key, mut value) in other.columns {
+            self.columns.entry(key)
+                .or_insert_with(|| value.empty().padded(rows))
+                .append(&mut value);
+        }
+        self.rows += other.rows;
+        for value in self.columns.values_mut() {
+            value.pad(self.rows);
+        }
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    /// Writes all the columns in a compact form, read back with `load`.
+    /// Has to be called after `sort`.
+    pub fn persist(&self, writer: &mut dyn Write) {
+        writer.write_u64::<LE>(self.rows as u64).unwrap();
+        writer.write_u32::<LE>(self.columns.len() as u32).unwrap();
+        for (key, column) in &self.columns {
+            writer.write_u32::<LE>(key.len() as u32).unwrap();
+            writer.write_all(key.as_bytes()).unwrap();
+            column.persist(writer);
+        }
+    }
+
+    pub fn load(reader: &mut dyn Read) -> Self {
+        let rows = reader.read_u64::<LE>().unwrap() as usize;
+        let mut columns = HashMap::new();
+        for _ in 0..reader.read_u32::<LE>().unwrap() {
+            let mut key = vec![0; reader.read_u32::<LE>().unwrap() as usize];
+            reader.read_exact(&mut key).unwrap();
+            columns.insert(String::from_utf8(key).unwrap(), ValVec::load(reader));
         }
+        Data{columns, rows}
     }
 
-    fn rows(&self) -> usize {
-        match self.0.values().next() {
-            Some(val) => val.len(),
-            None => 0,
+    /// Names of the dimension columns, in the order they are sorted and written.
+    fn dimensions(&self) -> Vec<String> {
+        let metrics = conf::vals.metrics();
+        let is_metric = |key: &String| metrics.iter().any(|m| &m.name == key || &m.field == key);
+        if !conf::vals.dimensions.is_empty() {
+            return conf::vals.dimensions.iter().map(|d| &d.name).filter(|d| !is_metric(d)).cloned().collect();
         }
+        let mut dimensions: Vec<_> = self.columns.keys()
+            .filter(|k| *k != TIME_COLUMN && !is_metric(k))
+            .cloned()
+            .collect();
+        dimensions.sort();
+        dimensions
     }
 
+    /// Turns metric fields into (not yet rolled up) metric columns,
+    /// truncates timestamps to `query_granularity`
+    /// and drops all the columns that are not going to be written.
     pub fn preaggregate(&mut self) {
         let rows = self.rows();
-        self.0.insert("count".to_string(), ValVec::Integer(vec![1; rows]));
+        let dimensions = self.dimensions();
+
+        let mut columns = HashMap::new();
+        for mut metric in conf::vals.metrics() {
+            // Metrics of merged segments are aggregated already, in their own columns.
+            if let Some(conf::Command::Merge{..}) = conf::vals.command {
+                metric = conf::Metric{aggregator: metric.aggregator.combining(), field: metric.name.clone(), ..metric};
+            }
+            let column = match (metric.aggregator, self.columns.get(&metric.field)) {
+                (conf::Aggregator::Count, _) => ValVec::Integer(vec![1; rows], vec![false; rows]),
+                (aggregator, Some(column)) => {
+                    let float = column.kind() != conf::DimensionType::Long;
+                    column.to_numeric(aggregator.kind(float)).unwrap_or_else(|| {
+                        warn!("field `{}` of metric `{}` is not numeric", metric.field, metric.name);
+                        ValVec::typed(aggregator.kind(false)).padded(rows)
+                    })
+                },
+                (aggregator, None) => ValVec::typed(aggregator.kind(false)).padded(rows),
+            };
+            columns.insert(metric.name, column);
+        }
+        let mut timestamp = self.columns.remove(TIME_COLUMN)
+            .unwrap_or_else(|| ValVec::Integer(Vec::new(), Vec::new()));
+        if let ValVec::Integer(ts, _) = &mut timestamp {
+            let granularity = conf::vals.query_granularity;
+            for t in ts.iter_mut() {
+                // Rows that cannot be bucketed are skipped when read, see `Conf::bucketable`.
+                *t = granularity.truncate(*t).expect("timestamp out of the range of dates");
+            }
+        }
+        columns.insert(TIME_COLUMN.to_string(), timestamp);
+        for dimension in dimensions {
+            let kind = conf::vals.dimension_type(&dimension).unwrap_or(conf::DimensionType::String);
+            let column = self.columns.remove(&dimension)
+                .unwrap_or_else(|| ValVec::typed(kind).padded(rows));
+            columns.insert(dimension, column);
+        }
+        self.columns = columns;
     }
 
+    /// Columns rows are sorted by: timestamp (and dimensions, when rolling up).
+    fn sort_columns(&self) -> Vec<&ValVec> {
+        let mut columns = vec![&self.columns[TIME_COLUMN]];
+        if !conf::vals.no_rollup {
+            columns.extend(self.dimensions().iter().map(|d| &self.columns[d]));
+        }
+        columns
+    }
+
+    /// Sorts rows by timestamp (and by dimensions, when rolling up).
     pub fn sort(&mut self) {
-        let mut perm: Vec<usize> = Vec::new();
-        if let ValVec::Integer(ts) = &self.0["timestamp"] {
-            perm = (0..ts.len()).collect();
-            perm.sort_unstable_by_key(|&i| &ts[i]);
-        }
-        let mut new0 = HashMap::new();
-        for (k, v) in self.0.drain() {
-            match v {
-                ValVec::IndexedString(mut is) => {
-                    is.sort_and_permute(&perm);
-                    new0.insert(k, ValVec::IndexedString(is));
-                },
-                ValVec::Integer(i) => {
-                    new0.insert(k, ValVec::Integer(perm.iter().map(|p| i[*p]).collect()));
-                },
-                ValVec::Float(f) => {
-                    new0.insert(k, ValVec::Float(perm.iter().map(|p| f[*p]).collect()));
-                },
+        for column in self.columns.values_mut() {
+            column.sort();
+        }
+        let mut perm: Vec<usize> = (0..self.rows).collect();
+        {
+            let columns = self.sort_columns();
+            perm.sort_unstable_by(|&a, &b| cmp_rows(&columns, a, b));
+        }
+        for column in self.columns.values_mut() {
+            column.permute(&perm);
+        }
+    }
+
+    /// Columns rows of (sorted) `parts` are ordered by all together, see `sort_columns`.
+    fn sort_keys(parts: &[&Data]) -> Vec<String> {
+        let mut keys = vec![TIME_COLUMN.to_string()];
+        if conf::vals.no_rollup || parts.is_empty() {
+            return keys;
+        }
+        if !conf::vals.dimensions.is_empty() {
+            keys.extend(parts[0].dimensions());
+            return keys;
+        }
+        let mut dimensions: Vec<String> = parts.iter().flat_map(|part| part.dimensions()).collect();
+        dimensions.sort();
+        dimensions.dedup();
+        keys.extend(dimensions);
+        keys
+    }
+
+    /// Compares row `a` with row `b` of `other` by `keys`, columns one of them
+    /// does not have being nulls. Both have to be sorted and agree on which
+    /// columns are numeric (see `numeric_columns`).
+    fn cmp_rows_with(&self, a: usize, other: &Data, b: usize, keys: &[String]) -> Ordering {
+        keys.iter().fold(Ordering::Equal, |o, key| o.then_with(|| {
+            match (self.columns.get(key), other.columns.get(key)) {
+                (Some(column), Some(other_column)) => column.cmp_rows_with(a, other_column, b),
+                (Some(column), None) => if column.is_null(a) { Ordering::Equal } else { Ordering::Greater },
+                (None, Some(other_column)) => if other_column.is_null(b) { Ordering::Equal } else { Ordering::Less },
+                (None, None) => Ordering::Equal,
+            }
+        }))
+    }
+
+    /// Names of the columns, each with whether it is numeric.
+    fn numeric_columns(&self) -> Vec<(String, bool)> {
+        self.columns.iter().map(|(key, column)| (key.clone(), column.is_numeric())).collect()
+    }
+
+    /// Turns numeric `keys` columns into strings, sorting rows over again.
+    fn make_strings(&mut self, keys: &[String]) {
+        for key in keys {
+            if let Some(column) = self.columns.get_mut(key) {
+                column.make_strings();
+            }
+        }
+        self.sort();
+    }
+
+    /// Copies a range of rows. Has to be called after `sort`.
+    fn select(&self, rows: Range<usize>) -> Data {
+        let mut columns = HashMap::new();
+        for (key, column) in &self.columns {
+            let mut column = column.select(rows.clone());
+            column.sort();
+            columns.insert(key.clone(), column);
+        }
+        Data{columns, rows: rows.len()}
+    }
+
+    /// Sorts dictionaries of parts `append`ed one after another in order,
+    /// which keeps rows sorted. Makes it ready for `rollup` and `write`.
+    fn sort_appended(&mut self) {
+        for column in self.columns.values_mut() {
+            column.sort();
+        }
+    }
+
+    /// Combines parts, each already sorted, into one sorted Data
+    /// (merging rows pairwise instead of sorting them all over again).
+    pub fn merge(parts: Vec<Data>) -> Data {
+        let mut data = Data::new();
+        let mut runs = Vec::with_capacity(parts.len());
+        let mut numeric = Vec::with_capacity(parts.len());
+        for part in parts {
+            numeric.extend(part.columns.iter().filter(|(_, c)| c.is_numeric()).map(|(k, _)| k.clone()));
+            let start = data.rows;
+            data.append(part);
+            runs.push((start..data.rows).collect::<Vec<_>>());
+        }
+        // Numbers turned into strings (by some part having strings there) are not in order any more.
+        if numeric.iter().any(|key| !data.columns[key].is_numeric()) {
+            data.sort();
+            return data;
+        }
+
+        for column in data.columns.values_mut() {
+            column.sort();
+        }
+        let perm = {
+            let columns = data.sort_columns();
+            while runs.len() > 1 {
+                runs = runs.chunks(2).map(|pair| match pair {
+                    [a, b] => merge_rows(&columns, a, b),
+                    _ => pair[0].clone(),
+                }).collect();
+            }
+            runs.pop().unwrap_or_default()
+        };
+        for column in data.columns.values_mut() {
+            column.permute(&perm);
+        }
+        data
+    }
+
+    /// Combines rows with equal timestamp and dimensions. Has to be called after `sort`.
+    pub fn rollup(&mut self) {
+        if conf::vals.no_rollup || self.rows == 0 {
+            return;
+        }
+
+        let mut starts = vec![0];
+        {
+            let dimensions = self.dimensions();
+            let ts = &self.columns[TIME_COLUMN];
+            let dims: Vec<&ValVec> = dimensions.iter().map(|d| &self.columns[d]).collect();
+            for row in 1..self.rows {
+                if ts.cmp_rows(row - 1, row) != Ordering::Equal ||
+                   dims.iter().any(|d| d.cmp_rows(row - 1, row) != Ordering::Equal) {
+                    starts.push(row);
+                }
             }
         }
-        self.0 = new0;
+        if starts.len() == self.rows {
+            return;
+        }
+
+        let metrics = conf::vals.metrics();
+        for (key, column) in self.columns.iter_mut() {
+            match metrics.iter().find(|m| &m.name == key) {
+                Some(metric) => column.aggregate(&starts, metric.aggregator.combining()),
+                None => column.permute(&starts),
+            }
+        }
+        self.rows = starts.len();
     }
 
-    pub fn write(&self, path: &PathBuf) {
+    /// Splits sorted rows into `segment_granularity` sized time chunks.
+    /// Has to be called after `sort`.
+    pub fn split(self) -> Vec<((i64, i64), Data)> {
+        let mut buckets = vec![];
+        if let ValVec::Integer(ts, _) = &self.columns[TIME_COLUMN] {
+            let granularity = conf::vals.segment_granularity;
+            let mut start = 0;
+            while start < ts.len() {
+                let interval_start = granularity.truncate(ts[start]).expect("timestamp out of the range of dates");
+                let interval_end = granularity.next(interval_start).expect("timestamp out of the range of dates");
+                let end = start + ts[start..].iter().take_while(|t| **t < interval_end).count();
+                buckets.push(((interval_start, interval_end), start..end));
+                start = end;
+            }
+        }
+        if buckets.len() == 1 {
+            return vec![(buckets[0].0, self)];
+        }
+
+        buckets.into_iter().map(|(interval, rows)| (interval, self.select(rows))).collect()
+    }
+
+    pub fn write(&self, path: &Path, interval: (i64, i64)) -> Result<(), String> {
+        let mut smoosher = Smoosher::new(path.to_path_buf(), conf::vals.smoosh_size);
+        self.write_data(&mut smoosher, interval)?;
+
         if let Some(compression) = conf::vals.zip {
             let mut version = vec![];
             let mut factory = vec![];
-            let mut data = vec![];
             let mut meta = vec![];
             self.write_version(&mut version);
             self.write_factory(&mut factory);
-            self.write_data(&mut data, &mut meta);
+            let chunks = smoosher.finish(&mut meta);
 
             let instant = Instant::now();
 
-            let zip = Zip::new(path.join("index.zip"), compression).unwrap();
-            zip.file_add("version.bin", &version).unwrap();
-            zip.file_add("factory.json", &factory).unwrap();
-            zip.file_add("00000.smoosh", &data).unwrap();
-            zip.file_add("meta.smoosh", &meta).unwrap();
-            zip.close().unwrap();
+            // Chunks are streamed in from the files already written, which are then removed.
+            let zipped = || -> Result<(), ZipError> {
+                let mut zip = Zip::create(path.join("index.zip"), compression)?;
+                zip.file_add("version.bin", &version)?;
+                zip.file_add("factory.json", &factory)?;
+                for n in 0..chunks {
+                    let chunk_path = path.join(smoosh::chunk_name(n));
+                    let mut entry = zip.start_file(&smoosh::chunk_name(n))?;
+                    io::copy(&mut fs::File::open(&chunk_path)?, &mut entry)?;
+                    entry.finish()?;
+                    fs::remove_file(chunk_path)?;
+                }
+                zip.file_add("meta.smoosh", &meta)?;
+                zip.finish()?;
+                Ok(())
+            };
+            zipped().map_err(|e| e.to_string())?;
 
             debug!("zipf `{:?}`", instant.elapsed());
 
-            return;
+            return Ok(());
         }
         let mut file = fs::File::create(path.join("version.bin")).unwrap();
         self.write_version(&mut file);
         file = fs::File::create(path.join("factory.json")).unwrap();
         self.write_factory(&mut file);
-        file = fs::File::create(path.join("00000.smoosh")).unwrap();
-        let mut meta_file = fs::File::create(path.join("meta.smoosh")).unwrap();
-        self.write_data(&mut file, &mut meta_file);
+        let mut meta_file = io::BufWriter::new(fs::File::create(path.join("meta.smoosh")).unwrap());
+        smoosher.finish(&mut meta_file);
+        Ok(())
     }
 
-    fn write_version(&self, writer: &mut Write) {
+    fn write_version(&self, writer: &mut dyn Write) {
         writer.write_u32::<BE>(9).unwrap();
     }
 
-    fn write_factory(&self, writer: &mut Write) {
+    fn write_factory(&self, writer: &mut dyn Write) {
         let factory = json!({"type": "mMapSegmentFactory"});
         serde_json::to_writer(writer, &factory).unwrap();
     }
 
-    fn write_data(&self, data_writer: &mut Write, meta_writer: &mut Write) {
-        let mut writer = BufWriter::new(data_writer);
-        let mut m_writer = BufWriter::new(meta_writer);
+    fn write_data(&self, smoosher: &mut Smoosher, interval: (i64, i64)) -> Result<(), String> {
+        let metrics = conf::vals.metrics();
+        let dimensions = self.dimensions();
 
-        let metrics = conf::vals.metrics.iter().collect::<IndexSet<_>>();
-        let mut dimensions = conf::vals.dimensions.iter().collect::<IndexSet<_>>();
-        if dimensions.is_empty() {
-            for dimension in self.0.keys() {
-                dimensions.insert(dimension);
-            }
-        }
-        dimensions = &dimensions - &metrics;
-
-        let mut offset = self.write_key(&mut writer, "timestamp");
-        write!(&mut m_writer, "v1,{},1\n__time,0,0,{}\n", std::i32::MAX, offset).unwrap();
+        self.write_key(smoosher, TIME_COLUMN, conf::vals.metric_compression())?;
 
         let mut cols_index = Vec::with_capacity((dimensions.len() + metrics.len()) * 4);
         let mut cols_index_header = Vec::with_capacity((dimensions.len() + metrics.len()) * 4);
         let mut dims_index_header = Vec::with_capacity(dimensions.len() * 4);
 
-        for key in metrics {
+        let mut aggregators = Vec::with_capacity(metrics.len());
+        for metric in &metrics {
+            let key = &metric.name;
             cols_index.write_u32::<BE>(0).unwrap();
             cols_index.write_all(key.as_bytes()).unwrap();
             cols_index_header.write_u32::<BE>(cols_index.len() as u32).unwrap();
 
-            let next_offset = offset + self.write_key(&mut writer, key);
-            write!(&mut m_writer, "{},0,{},{}\n", key, offset, next_offset).unwrap();
-            offset = next_offset;
+            self.write_key(smoosher, key, conf::vals.metric_compression())?;
+
+            // As configured, Druid takes the combining ones from these itself.
+            aggregators.push(match metric.aggregator {
+                conf::Aggregator::Count => json!({"type": "count", "name": key}),
+                aggregator => json!({
+                    "type": aggregator.type_name(self.columns[key].kind()),
+                    "name": key,
+                    "fieldName": metric.field,
+                    "expression": Value::Null,
+                }),
+            });
         }
-        let mut metas = IndexSet::new();
         let cols_index_offset = cols_index.len();
-        for key in dimensions {
+        for key in &dimensions {
             cols_index.write_u32::<BE>(0).unwrap();
             cols_index.write_all(key.as_bytes()).unwrap();
             cols_index_header.write_u32::<BE>(cols_index.len() as u32).unwrap();
             dims_index_header.write_u32::<BE>(cols_index[cols_index_offset..].len() as u32).unwrap();
 
-            let next_offset = offset + self.write_key(&mut writer, key);
-            metas.insert(format!("{},0,{},{}\n", key, offset, next_offset));
-            offset = next_offset;
+            self.write_key(smoosher, key, conf::vals.dimension_compression())?;
         }
 
-        self.write_columns_index(&mut writer, &cols_index, &cols_index_header, 1);
-        self.write_columns_index(&mut writer, &cols_index[cols_index_offset..], &dims_index_header, 2);
+        let mut index = vec![];
+        self.write_columns_index(&mut index, &cols_index, &cols_index_header, metrics.len() + dimensions.len());
+        self.write_columns_index(&mut index, &cols_index[cols_index_offset..], &dims_index_header, dimensions.len());
 
-        if let ValVec::Integer(ts) = &self.0["timestamp"] {
-            writer.write_i64::<BE>(ts[0]).unwrap();
-            writer.write_i64::<BE>(ts[0] + 86_400_000).unwrap(); // XXX: DAY granularity
-        }
+        index.write_i64::<BE>(interval.0).unwrap();
+        index.write_i64::<BE>(interval.1).unwrap();
+
+        let bitmap_type = conf::vals.bitmap.to_json().to_string();
+        index.write_u32::<BE>(bitmap_type.len() as u32).unwrap();
+        index.write_all(bitmap_type.as_bytes()).unwrap();
+        smoosher.add("index.drd", &index)?;
 
-        let bitmap_type = json!({
-            "type": "concise",
-        }).to_string();
         let generic_meta = json!({
             "container": {},
-            "aggregators": [{
-                "type": "longSum",
-                "name": "count",
-                "fieldName": "count",
-                "expression": Value::Null,
-            }],
+            "aggregators": aggregators,
             "timestampSpec": {
-                "column": "timestamp",
-                "format": "millis",
-                "missingValue": Value::Null,
+                "column": conf::vals.timestamp_column,
+                "format": conf::vals.timestamp_format.name(),
+                "missingValue": conf::vals.timestamp_missing.map(granularity::format_instant),
             },
-            "queryGranularity": {
-                "type": "none",
-            },
-            "rollup": true,
+            "queryGranularity": conf::vals.query_granularity.to_json(),
+            "rollup": !conf::vals.no_rollup,
         }).to_string();
+        smoosher.add("metadata.drd", generic_meta.as_bytes())
+    }
 
-        let new_offset =
-            offset +
-            cols_index.len() +
-            cols_index_header.len() +
-            dims_index_header.len() +
-            (cols_index.len() - cols_index_offset) +
-            20 + // index metadata (from `write_columns_index`, 1+1+4+4)
-            16 + // segment boundaries (from `if "timestamp"` above, 8+8)
-            4 + // `bitmap_type` length field (`18` below)
-            bitmap_type.len()
-        ;
-        metas.insert(format!("index.drd,0,{},{}\n", offset, new_offset));
-        metas.insert(format!("metadata.drd,0,{},{}\n", new_offset, new_offset + generic_meta.len()));
-        metas.sort();
-        for meta in metas {
-            m_writer.write_all(meta.as_bytes()).unwrap();
-        }
-
-        writer.write_u32::<BE>(18).unwrap();
-        writer.write_all(bitmap_type.as_bytes()).unwrap();
-        writer.write_all(generic_meta.as_bytes()).unwrap();
-    }
-
-    fn write_key(&self, writer: &mut Write, key: &str) -> usize {
-        let mut column = vec![];
-        match &self.0[key] {
-            ValVec::IndexedString(is) => {
-                column.write_u32::<BE>(META_TYPES["string"].len() as u32).unwrap();
-                column.write_all(META_TYPES["string"].as_bytes()).unwrap();
-
-                is.write(&mut column);
-            },
-            ValVec::Integer(i) => write_numeric(&mut column, &META_TYPES["long"], i),
-            ValVec::Float(f) => write_numeric(&mut column, &META_TYPES["double"], f),
+    /// Adds the column to the smoosh, along with any extra entries it needs.
+    fn write_key(&self, smoosher: &mut Smoosher, key: &str, compression: conf::Compression) -> Result<(), String> {
+        match &self.columns[key] {
+            ValVec::IndexedString(is) => is.write(smoosher, key, &META_TYPES["string"], compression),
+            ValVec::MultiIndexedString(is) => is.write(smoosher, key, &META_TYPES["multi_string"], compression),
+            ValVec::Integer(i, n) => smoosher.add_with(key, |writer| {
+                write_longs(writer, &META_TYPES["long"], i, n, compression)
+            }),
+            ValVec::Float(f, n) => smoosher.add_with(key, |writer| {
+                write_numeric(writer, &META_TYPES["double"], f, n, compression)
+            }),
+            ValVec::Float32(f, n) => smoosher.add_with(key, |writer| {
+                write_numeric(writer, &META_TYPES["float"], f, n, compression)
+            }),
         }
-        writer.write_all(&column).unwrap();
-        column.len()
     }
 
-    fn write_columns_index(&self, writer: &mut Write, index: &[u8], header: &[u8], delta: u32) {
+    fn write_columns_index(&self, writer: &mut dyn Write, index: &[u8], header: &[u8], count: usize) {
         writer.write_u8(1).unwrap(); // GenericIndexed.VERSION_ONE
         writer.write_u8(0).unwrap(); // GenericIndexed.REVERSE_LOOKUP_DISALLOWED
         writer.write_u32::<BE>((header.len() + index.len() + 4) as u32).unwrap(); // + Integer.BYTES
-        writer.write_u32::<BE>(self.0.len() as u32 - delta).unwrap(); // GenericIndexed.size (number of columns/dimensions, without timestamp)
-        writer.write_all(&header).unwrap();
-        writer.write_all(&index).unwrap();
+        writer.write_u32::<BE>(count as u32).unwrap(); // GenericIndexed.size (number of columns/dimensions, without timestamp)
+        writer.write_all(header).unwrap();
+        writer.write_all(index).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that hardly compress, the same ones every run (xorshift).
+    pub fn noise(len: usize) -> Vec<u8> {
+        let mut state = 1u32;
+        (0..len).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect()
+    }
+
+    // Rows numbered by `id`, each with `key` set to `<key><id>`.
+    fn part(key: &str, ids: Range<i64>) -> Data {
+        let mut data = Data::new();
+        for id in ids {
+            data.add_i("id".to_string(), id);
+            data.add_s(key.to_string(), format!("{}{}", key, id));
+            data.next_row();
+        }
+        data
+    }
+
+    // Rolls `data` up and writes it as a segment, returning the one read back.
+    fn written(name: &str, mut data: Data) -> Data {
+        let dir = std::env::temp_dir().join(format!("dsp-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        data.preaggregate();
+        data.sort();
+        data.rollup();
+        data.write(&dir, (0, 86_400_000)).unwrap();
+        let (_, read) = segment::read(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        read
+    }
+
+    fn strings(data: &Data, key: &str) -> Vec<Option<String>> {
+        match &data.columns[key] {
+            ValVec::IndexedString(is) => is.values(),
+            column => panic!("`{}` is not a string column: {:?}", key, column),
+        }
+    }
+
+    #[test]
+    fn append_keeps_rows_aligned_across_disjoint_columns() {
+        let mut data = Data::new();
+        data.append(part("a", 0..2));
+        data.append(part("b", 2..5));
+        data.append(part("a", 5..6));
+        assert_eq!(data.rows(), 6);
+
+        let s = |v: &str| Some(v.to_string());
+        let a = vec![s("a0"), s("a1"), None, None, None, s("a5")];
+        let b = vec![None, None, s("b2"), s("b3"), s("b4"), None];
+        assert_eq!(strings(&data, "a"), a);
+        assert_eq!(strings(&data, "b"), b);
+
+        // Merging the parts (as sorting does first) keeps them in place.
+        for column in data.columns.values_mut() {
+            column.sort();
+        }
+        match &data.columns["id"] {
+            ValVec::Integer(ids, _) => assert_eq!(*ids, (0..6).collect::<Vec<_>>()),
+            column => panic!("`id` is not an integer column: {:?}", column),
+        }
+        assert_eq!(strings(&data, "a"), a);
+        assert_eq!(strings(&data, "b"), b);
+    }
+
+    #[test]
+    fn add_array_drops_and_counts_elements_that_are_not_scalars() {
+        let mut data = Data::new();
+        let array: Value = serde_json::from_str(r#"["a", 1, 2.5, true, null, {"b": 1}, ["c"]]"#).unwrap();
+        match array {
+            Value::Array(a) => assert_eq!(data.add_array("tags".to_string(), a), 3),
+            _ => unreachable!(),
+        }
+        data.next_row();
+        assert_eq!(data.add_array("tags".to_string(), vec![Value::Null]), 1);
+        data.next_row();
+
+        match &data.columns["tags"] {
+            ValVec::MultiIndexedString(is) => {
+                let row = |n| is.row(n).cloned().collect::<Vec<_>>();
+                let s = |v: &str| Some(v.to_string());
+                assert_eq!(row(0), vec![s("a"), s("1"), s("2.5"), s("true")]);
+                // Nothing left of it, a null.
+                assert_eq!(row(1), vec![None]);
+            },
+            column => panic!("`tags` is not a multi-value column: {:?}", column),
+        }
+    }
+
+    #[test]
+    fn missing_values_are_written_as_zeros_and_empty_strings_by_default() {
+        let mut data = Data::new();
+        let rows = [(1, Some(7), Some(0.5), Some("a")), (2, None, None, None), (3, Some(0), Some(1.5), Some(""))];
+        for (ts, n, x, page) in rows {
+            data.add_i(TIME_COLUMN.to_string(), ts);
+            if let Some(n) = n {
+                data.add_i("n".to_string(), n);
+            }
+            if let Some(x) = x {
+                data.add_f("x".to_string(), x);
+            }
+            if let Some(page) = page {
+                data.add_s("page".to_string(), page.to_string());
+            }
+            data.next_row();
+        }
+        match &data.columns["n"] {
+            ValVec::Integer(_, nulls) => assert_eq!(*nulls, vec![false, true, false]),
+            column => panic!("`n` is not an integer column: {:?}", column),
+        }
+
+        let read = written("nulls", data);
+        match (&read.columns["n"], &read.columns["x"]) {
+            (ValVec::Integer(n, n_nulls), ValVec::Float(x, x_nulls)) => {
+                assert_eq!((n, x), (&vec![7, 0, 0], &vec![0.5, 0., 1.5]));
+                assert!(!n_nulls.iter().chain(x_nulls).any(|null| *null));
+            },
+            columns => panic!("`n` and `x` are not numeric columns: {:?}", columns),
+        }
+        // Druid does not tell "" from null, without SQL compatible null handling.
+        assert_eq!(strings(&read, "page"), vec![Some("a".to_string()), None, None]);
+    }
+
+    #[test]
+    fn rollup_counts_rows_of_equal_timestamp_and_dimensions() {
+        let mut data = Data::new();
+        for (ts, page) in &[(2, "b"), (1, "a"), (2, "b"), (1, "b"), (2, "b")] {
+            data.add_i(TIME_COLUMN.to_string(), *ts);
+            data.add_s("page".to_string(), page.to_string());
+            data.next_row();
+        }
+        data.preaggregate();
+        data.sort();
+        data.rollup();
+
+        assert_eq!(data.rows(), 3);
+        let s = |v: &str| Some(v.to_string());
+        assert_eq!(strings(&data, "page"), vec![s("a"), s("b"), s("b")]);
+        match (&data.columns[TIME_COLUMN], &data.columns["count"]) {
+            (ValVec::Integer(ts, _), ValVec::Integer(counts, _)) => assert_eq!((ts, counts), (&vec![1, 1, 2], &vec![1, 1, 3])),
+            columns => panic!("`__time` and `count` are not integer columns: {:?}", columns),
+        }
+    }
+
+    #[test]
+    fn aggregators_combine_rows_of_each_group() {
+        let (values, nulls, starts) = ([3, 1, 2, 7, 5], [false; 5], [0, 3]);
+        let aggregated = [(conf::Aggregator::LongSum, [6, 12]), (conf::Aggregator::LongMin, [1, 5]), (conf::Aggregator::LongMax, [3, 7])];
+        for (aggregator, expected) in &aggregated {
+            assert_eq!(aggregate(&values, &nulls, &starts, *aggregator), (expected.to_vec(), vec![false; 2]));
+        }
+        let values = [0.5, -1.5, 4.];
+        assert_eq!(aggregate(&values, &nulls[..3], &[0], conf::Aggregator::DoubleSum), (vec![3.], vec![false]));
+        assert_eq!(aggregate(&values, &nulls[..3], &[0, 1], conf::Aggregator::DoubleMin), (vec![0.5, -1.5], vec![false; 2]));
+        // Counts are summed up when combined.
+        assert_eq!(conf::Aggregator::Count.combining(), conf::Aggregator::LongSum);
+    }
+
+    #[test]
+    fn split_gives_a_segment_per_day_with_rows() {
+        let day = 86_400_000;
+        let mut data = Data::new();
+        for (n, ts) in [day + 1, 4 * day - 1, 0, 3 * day, day].iter().enumerate() {
+            data.add_i(TIME_COLUMN.to_string(), *ts);
+            data.add_s("a".to_string(), format!("a{}", n));
+            data.next_row();
+        }
+        data.sort();
+
+        let segments: Vec<_> = data.split().into_iter()
+            .map(|(interval, segment)| (interval, strings(&segment, "a")))
+            .collect();
+        let s = |v: &str| Some(v.to_string());
+        // No segment for the day without rows.
+        assert_eq!(segments, vec![
+            ((0, day), vec![s("a2")]),
+            ((day, 2 * day), vec![s("a4"), s("a0")]),
+            ((3 * day, 4 * day), vec![s("a3"), s("a1")]),
+        ]);
+    }
+
+    #[test]
+    fn typed_dimensions_coerce_values_storing_nulls_for_the_rest() {
+        use conf::DimensionType;
+
+        let mut data = Data::new();
+        let rows = [
+            (json!(12), json!(7), json!("0.5"), json!(1.5)),
+            (json!("13"), json!(3.7), json!(2), json!("x")),
+            (json!([1, "a"]), json!(" 8 "), json!("y"), json!(null)),
+            (json!({"a": 1}), json!(true), json!(null), json!(-2)),
+        ];
+        let mut failed = vec![];
+        for (s, l, d, f) in rows.iter().cloned() {
+            let typed = [("s", s, DimensionType::String), ("l", l, DimensionType::Long), ("d", d, DimensionType::Double), ("f", f, DimensionType::Float)];
+            for (key, value, kind) in typed {
+                if !data.add_typed(key.to_string(), value, kind) {
+                    failed.push(key);
+                }
+            }
+            data.next_row();
+        }
+        assert_eq!(failed, ["f", "d", "s", "l"]);
+
+        match &data.columns["s"] {
+            ValVec::MultiIndexedString(is) => {
+                let rows: Vec<Vec<Option<String>>> = (0..4).map(|n| is.row(n).cloned().collect()).collect();
+                let s = |v: &str| Some(v.to_string());
+                assert_eq!(rows, vec![vec![s("12")], vec![s("13")], vec![s("1"), s("a")], vec![None]]);
+            },
+            column => panic!("`s` is not a multi-value column: {:?}", column),
+        }
+        match (&data.columns["l"], &data.columns["d"], &data.columns["f"]) {
+            (ValVec::Integer(l, l_nulls), ValVec::Float(d, d_nulls), ValVec::Float32(f, f_nulls)) => {
+                assert_eq!((l, l_nulls), (&vec![7, 3, 8, 0], &vec![false, false, false, true]));
+                assert_eq!((d, d_nulls), (&vec![0.5, 2., 0., 0.], &vec![false, false, true, true]));
+                assert_eq!((f, f_nulls), (&vec![1.5, 0., 0., -2.], &vec![false, true, true, false]));
+            },
+            columns => panic!("`l`, `d` and `f` are not of their types: {:?}", columns),
+        }
+    }
+
+    #[test]
+    fn mixed_integers_and_floats_widen_to_doubles() {
+        let column = |values: &[Value]| {
+            let mut data = Data::new();
+            for value in values {
+                match value.as_i64() {
+                    Some(i) => data.add_i("n".to_string(), i),
+                    None => data.add_f("n".to_string(), value.as_f64().unwrap()),
+                }
+                data.next_row();
+            }
+            data
+        };
+        let doubles = |data: &Data| match &data.columns["n"] {
+            ValVec::Float(f, _) => f.clone(),
+            column => panic!("`n` is not a double column: {:?}", column),
+        };
+        assert_eq!(doubles(&column(&[json!(1), json!(2.5), json!(3)])), vec![1., 2.5, 3.]);
+        assert_eq!(doubles(&column(&[json!(0.5), json!(2)])), vec![0.5, 2.]);
+
+        // Parts read by different workers.
+        let mut data = column(&[json!(1), json!(2)]);
+        data.append(column(&[json!(0.5)]));
+        assert_eq!(doubles(&data), vec![1., 2., 0.5]);
+        let mut data = column(&[json!(0.5)]);
+        data.append(column(&[json!(1), json!(2)]));
+        assert_eq!(doubles(&data), vec![0.5, 1., 2.]);
+    }
+
+    #[test]
+    fn floats_are_written_and_read_back_in_32_bits() {
+        let mut data = Data::new();
+        for (ts, f) in &[(1, 0.1), (2, -2.5), (3, 1e30)] {
+            data.add_i(TIME_COLUMN.to_string(), *ts);
+            data.add_typed("f".to_string(), json!(f), conf::DimensionType::Float);
+            data.next_row();
+        }
+        let read = written("floats", data);
+        match &read.columns["f"] {
+            ValVec::Float32(f, _) => assert_eq!(*f, vec![0.1f32, -2.5, 1e30]),
+            column => panic!("`f` is not a float column: {:?}", column),
+        }
+        assert_eq!(conf::Aggregator::FloatSum.kind(false), conf::DimensionType::Float);
+        assert_eq!(conf::Aggregator::FloatMax.type_name(conf::DimensionType::Float), "floatMax");
+    }
+
+    #[test]
+    fn add_column_pads_to_the_longest_column() {
+        let mut data = part("a", 0..2);
+        data.add_column("n".to_string(), ValVec::Integer(vec![7, 8, 9], vec![false; 3]));
+        let mut short = IS::new();
+        short.add_s("x".to_string());
+        data.add_column("s".to_string(), ValVec::IndexedString(short));
+
+        assert_eq!(data.rows(), 3);
+        assert_eq!(strings(&data, "a"), vec![Some("a0".to_string()), Some("a1".to_string()), None]);
+        assert_eq!(strings(&data, "s"), vec![Some("x".to_string()), None, None]);
+        match &data.columns["id"] {
+            ValVec::Integer(_, nulls) => assert_eq!(*nulls, vec![false, false, true]),
+            column => panic!("`id` is not an integer column: {:?}", column),
+        }
     }
 }
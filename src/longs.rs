@@ -0,0 +1,244 @@
+use std::io::{Read, Write};
+
+use byteorder::{BE, ReadBytesExt, WriteBytesExt};
+use indexmap::IndexSet;
+
+// CompressionFactory.MAX_TABLE_SIZE
+static MAX_TABLE_SIZE: usize = 256;
+// Sizes supported by VSizeLongSerde
+static BITS: [u32; 13] = [1, 2, 4, 8, 12, 16, 20, 24, 32, 40, 48, 56, 64];
+
+/// CompressionFactory.LongEncodingFormat, other than plain longs.
+pub enum Encoding {
+    // Values stored as offsets from `base`.
+    Delta { base: i64, bits: u32 },
+    // Values stored as indexes into `table`.
+    Table { table: IndexSet<i64>, bits: u32 },
+}
+
+impl Encoding {
+    /// Picks the smallest of the encodings, the way Druid's `auto` does
+    /// (`None` meaning plain longs).
+    pub fn analyse(values: &[i64]) -> Option<Self> {
+        let (min, max) = match (values.iter().min(), values.iter().max()) {
+            (Some(min), Some(max)) => (*min, *max),
+            _ => return None,
+        };
+        let delta_bits = max.checked_sub(min).map(|delta| bits_for(delta as u64)).unwrap_or(64);
+
+        let mut table = IndexSet::new();
+        for value in values {
+            table.insert(*value);
+            if table.len() > MAX_TABLE_SIZE {
+                break;
+            }
+        }
+        if table.len() <= MAX_TABLE_SIZE {
+            let table_bits = bits_for(table.len() as u64 - 1);
+            if table_bits < delta_bits {
+                return Some(Encoding::Table{table, bits: table_bits});
+            }
+        }
+        if delta_bits < 64 {
+            return Some(Encoding::Delta{base: min, bits: delta_bits});
+        }
+        None
+    }
+
+    /// Encoding id followed by its header.
+    pub fn write_header(&self, out: &mut dyn Write) {
+        match self {
+            Encoding::Delta{base, bits} => {
+                out.write_u8(0x0).unwrap();
+                out.write_u8(1).unwrap(); // DELTA_ENCODING_VERSION
+                out.write_i64::<BE>(*base).unwrap();
+                out.write_u32::<BE>(*bits).unwrap();
+            },
+            Encoding::Table{table, ..} => {
+                out.write_u8(0x1).unwrap();
+                out.write_u8(1).unwrap(); // TABLE_ENCODING_VERSION
+                out.write_u32::<BE>(table.len() as u32).unwrap();
+                for value in table {
+                    out.write_i64::<BE>(*value).unwrap();
+                }
+            },
+        }
+    }
+
+    /// Reads back what `write_header` wrote.
+    pub fn read_header(reader: &mut dyn Read) -> Self {
+        let id = reader.read_u8().unwrap();
+        reader.read_u8().unwrap(); // version
+        match id {
+            0x0 => Encoding::Delta{
+                base: reader.read_i64::<BE>().unwrap(),
+                bits: reader.read_u32::<BE>().unwrap(),
+            },
+            0x1 => {
+                let size = reader.read_u32::<BE>().unwrap();
+                let table: IndexSet<i64> = (0..size).map(|_| reader.read_i64::<BE>().unwrap()).collect();
+                let bits = bits_for(table.len() as u64 - 1);
+                Encoding::Table{table, bits}
+            },
+            _ => panic!("unsupported long encoding {}", id),
+        }
+    }
+
+    fn bits(&self) -> u32 {
+        match self {
+            Encoding::Delta{bits, ..} | Encoding::Table{bits, ..} => *bits,
+        }
+    }
+
+    /// Number of values per (64KiB) block, a power of 2.
+    pub fn size_per(&self) -> usize {
+        let mut size = 1;
+        while serialized_size(self.bits(), size * 2) <= 0x10000 {
+            size *= 2;
+        }
+        size
+    }
+
+    /// Bit packs `values` (most significant bits first, as VSizeLongSerde does).
+    pub fn write_block(&self, out: &mut Vec<u8>, values: &[i64]) {
+        let bits = self.bits();
+        let mut acc: u128 = 0;
+        let mut acc_bits = 0;
+        for value in values {
+            let v = match self {
+                Encoding::Delta{base, ..} => value.wrapping_sub(*base) as u64,
+                Encoding::Table{table, ..} => table.get_full(value).unwrap().0 as u64,
+            };
+            acc = (acc << bits) | v as u128;
+            acc_bits += bits;
+            while acc_bits >= 8 {
+                acc_bits -= 8;
+                out.write_u8((acc >> acc_bits) as u8).unwrap();
+            }
+        }
+        if acc_bits > 0 {
+            out.write_u8((acc << (8 - acc_bits)) as u8).unwrap();
+        }
+        // Readers may load a whole int/long at the last value.
+        out.write_u32::<BE>(0).unwrap();
+    }
+
+    /// Unpacks `count` values of a block written by `write_block`.
+    pub fn read_block(&self, block: &[u8], count: usize, out: &mut Vec<i64>) {
+        let bits = self.bits();
+        let mut bytes = block.iter();
+        let mut acc: u128 = 0;
+        let mut acc_bits = 0;
+        for _ in 0..count {
+            while acc_bits < bits {
+                acc = (acc << 8) | *bytes.next().unwrap() as u128;
+                acc_bits += 8;
+            }
+            acc_bits -= bits;
+            let v = (acc >> acc_bits) as u64 & (u64::MAX >> (64 - bits));
+            acc &= (1 << acc_bits) - 1;
+            out.push(match self {
+                Encoding::Delta{base, ..} => base.wrapping_add(v as i64),
+                Encoding::Table{table, ..} => *table.get_index(v as usize).unwrap(),
+            });
+        }
+    }
+}
+
+fn bits_for(max: u64) -> u32 {
+    let needed = 64 - max.leading_zeros();
+    *BITS.iter().find(|b| **b >= needed).unwrap()
+}
+
+fn serialized_size(bits: u32, values: usize) -> usize {
+    (bits as usize * values + 7) / 8 + 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(encoding: &Encoding, values: &[i64]) -> Vec<u8> {
+        let mut block = vec![];
+        encoding.write_block(&mut block, values);
+        assert_eq!(block.len(), serialized_size(encoding.bits(), values.len()));
+        let mut read = vec![];
+        encoding.read_block(&block, values.len(), &mut read);
+        assert_eq!(read, values);
+        block
+    }
+
+    #[test]
+    fn every_bit_width_round_trips() {
+        for bits in &BITS {
+            let max = u64::MAX >> (64 - bits);
+            let base: i64 = -1_000_000;
+            let values: Vec<i64> = [0, max, 1, max / 2, max - 1, 0, max].iter()
+                .map(|v| base.wrapping_add(*v as i64))
+                .collect();
+            let encoding = Encoding::Delta{base, bits: *bits};
+            round_trip(&encoding, &values);
+            assert_eq!(bits_for(max), *bits);
+        }
+        assert_eq!(bits_for(3), 2);
+        assert_eq!(bits_for(5), 4);
+        assert_eq!(bits_for(1 << 32), 40);
+    }
+
+    #[test]
+    fn negative_deltas_are_from_the_least_value() {
+        let values: Vec<i64> = (-1000..-600).rev().chain(-3000..-2990).collect();
+        match Encoding::analyse(&values) {
+            Some(Encoding::Delta{base, bits}) => assert_eq!((base, bits), (-3000, 12)),
+            _ => panic!("not delta encoded"),
+        }
+        round_trip(&Encoding::analyse(&values).unwrap(), &values);
+
+        // Too many values for a table, with deltas needing all 64 bits: plain longs.
+        let values: Vec<i64> = (0..300).map(|n| i64::MIN + n).chain(Some(i64::MAX)).collect();
+        assert!(Encoding::analyse(&values).is_none());
+    }
+
+    #[test]
+    fn tables_hold_up_to_256_values() {
+        let values: Vec<i64> = (0..256).map(|n| n * 1_000_000_007).collect();
+        match Encoding::analyse(&values) {
+            Some(Encoding::Table{table, bits}) => assert_eq!((table.len(), bits), (256, 8)),
+            _ => panic!("not table encoded"),
+        }
+        let encoding = Encoding::analyse(&values).unwrap();
+        round_trip(&encoding, &values);
+        let mut header = vec![];
+        encoding.write_header(&mut header);
+        assert_eq!(&header[..6], &[0x1, 1, 0, 0, 1, 0]);
+        assert_eq!(header.len(), 6 + 256 * 8);
+        match Encoding::read_header(&mut &header[..]) {
+            Encoding::Table{table, bits} => assert_eq!((table.len(), bits), (256, 8)),
+            _ => panic!("not read back as a table"),
+        }
+
+        let values: Vec<i64> = (0..257).map(|n| n * 1_000_000_007).collect();
+        match Encoding::analyse(&values) {
+            Some(Encoding::Delta{base, bits}) => assert_eq!((base, bits), (0, 40)),
+            _ => panic!("not delta encoded"),
+        }
+    }
+
+    #[test]
+    fn values_are_packed_as_druid_reads_them() {
+        // Most significant bits first, the last byte padded with zeros,
+        // followed by 4 more bytes.
+        let packed = |bits: u32, values: &[i64]| round_trip(&Encoding::Delta{base: 0, bits}, values);
+        assert_eq!(packed(1, &[1, 0, 1, 1, 0, 0, 0, 0, 1]), [0xb0, 0x80, 0, 0, 0, 0]);
+        assert_eq!(packed(2, &[3, 0, 1, 2, 1]), [0xc6, 0x40, 0, 0, 0, 0]);
+        assert_eq!(packed(4, &[1, 2, 3]), [0x12, 0x30, 0, 0, 0, 0]);
+        assert_eq!(packed(12, &[0xabc, 0x123]), [0xab, 0xc1, 0x23, 0, 0, 0, 0]);
+        assert_eq!(packed(20, &[0xabcde, 0x12345]), [0xab, 0xcd, 0xe1, 0x23, 0x45, 0, 0, 0, 0]);
+        assert_eq!(packed(40, &[0x01_0203_0405]), [1, 2, 3, 4, 5, 0, 0, 0, 0]);
+        assert_eq!(packed(64, &[-2]), [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0, 0, 0, 0]);
+
+        let mut header = vec![];
+        Encoding::Delta{base: -2, bits: 12}.write_header(&mut header);
+        assert_eq!(header, [0x0, 1, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe, 0, 0, 0, 12]);
+    }
+}
@@ -0,0 +1,172 @@
+use byteorder::{BE, WriteBytesExt};
+
+// LZFChunk.MAX_CHUNK_LEN
+static MAX_CHUNK: usize = 0xffff;
+static MAX_OFFSET: usize = 1 << 13;
+static MAX_MATCH: usize = (1 << 8) + (1 << 3);
+static HASH_LOG: u32 = 14;
+
+/// Compresses `data` into the chunked format read by `compress-lzf`'s
+/// `ChunkDecoder` (which is what Druid's `LZFDecompressor` uses).
+pub fn compress(out: &mut Vec<u8>, data: &[u8]) {
+    for chunk in data.chunks(MAX_CHUNK) {
+        let encoded = encode(chunk);
+        out.extend_from_slice(b"ZV");
+        if encoded.len() < chunk.len() {
+            out.write_u8(1).unwrap(); // BLOCK_TYPE_COMPRESSED
+            out.write_u16::<BE>(encoded.len() as u16).unwrap();
+            out.write_u16::<BE>(chunk.len() as u16).unwrap();
+            out.extend_from_slice(&encoded);
+        } else {
+            out.write_u8(0).unwrap(); // BLOCK_TYPE_NON_COMPRESSED
+            out.write_u16::<BE>(chunk.len() as u16).unwrap();
+            out.extend_from_slice(chunk);
+        }
+    }
+}
+
+/// Reverses `compress` (any `ChunkEncoder` output, really).
+pub fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(MAX_CHUNK);
+    let mut i = 0;
+    while i + 5 <= data.len() && &data[i..i + 2] == b"ZV" {
+        let len = (data[i + 3] as usize) << 8 | data[i + 4] as usize;
+        match data[i + 2] {
+            0 => {
+                out.extend_from_slice(&data[i + 5..i + 5 + len]);
+                i += 5 + len;
+            },
+            _ => {
+                decode(&mut out, &data[i + 7..i + 7 + len]);
+                i += 7 + len;
+            },
+        }
+    }
+    out
+}
+
+// Plain liblzf: literal runs of up to 32 bytes, back references of 3 to 264
+// bytes up to 8KiB back.
+fn encode(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len());
+    // Last position (plus one) of every 3-byte hash, `0` meaning none.
+    let mut table = vec![0; 1 << HASH_LOG];
+    let mut literals = 0;
+    let mut i = 0;
+    while i + 2 < input.len() {
+        let h = hash(&input[i..i + 3]);
+        let candidate = table[h];
+        table[h] = i + 1;
+        if candidate > 0 && i - candidate < MAX_OFFSET && input[candidate - 1..candidate + 2] == input[i..i + 3] {
+            let reference = candidate - 1;
+            let max = MAX_MATCH.min(input.len() - i);
+            let mut len = 3;
+            while len < max && input[reference + len] == input[i + len] {
+                len += 1;
+            }
+            write_literals(&mut out, &input[i - literals..i]);
+            literals = 0;
+
+            let offset = i - reference - 1;
+            let len = len - 2;
+            if len < 7 {
+                out.write_u8(((len << 5) | (offset >> 8)) as u8).unwrap();
+            } else {
+                out.write_u8(((7 << 5) | (offset >> 8)) as u8).unwrap();
+                out.write_u8((len - 7) as u8).unwrap();
+            }
+            out.write_u8(offset as u8).unwrap();
+            i += len + 2;
+        } else {
+            literals += 1;
+            i += 1;
+        }
+    }
+    write_literals(&mut out, &input[i - literals..]);
+    out
+}
+
+fn write_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for run in literals.chunks(32) {
+        out.write_u8((run.len() - 1) as u8).unwrap();
+        out.extend_from_slice(run);
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = (bytes[0] as u32) << 16 | (bytes[1] as u32) << 8 | bytes[2] as u32;
+    (v.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+fn decode(out: &mut Vec<u8>, input: &[u8]) {
+    let mut i = 0;
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            out.extend_from_slice(&input[i..i + ctrl + 1]);
+            i += ctrl + 1;
+            continue;
+        }
+        let mut len = ctrl >> 5;
+        if len == 7 {
+            len += input[i] as usize;
+            i += 1;
+        }
+        let reference = out.len() - ((ctrl & 0x1f) << 8) - input[i] as usize - 1;
+        i += 1;
+        // Back references may overlap what they produce.
+        for j in reference..reference + len + 2 {
+            let b = out[j];
+            out.push(b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let mut compressed = vec![];
+        compress(&mut compressed, data);
+        assert_eq!(decompress(&compressed), data);
+        compressed
+    }
+
+    #[test]
+    fn round_trips() {
+        assert!(round_trip(b"").is_empty());
+
+        // Incompressible bytes go in uncompressed chunks.
+        let noise = ::tests::noise(100_000);
+        let compressed = round_trip(&noise);
+        assert_eq!(&compressed[..5], b"ZV\x00\xff\xff");
+        assert_eq!(compressed.len(), noise.len() + 2 * 5);
+
+        let text: Vec<u8> = (0..200_000).flat_map(|n| format!("row {} of page p{}\n", n, n % 300).into_bytes()).collect();
+        assert!(round_trip(&text).len() < text.len() / 2);
+    }
+
+    #[test]
+    fn runs_longer_than_a_back_reference_take_several() {
+        for len in &[264, 265, 266, 1000, MAX_CHUNK, MAX_CHUNK + 1] {
+            let data = vec![7; *len];
+            let compressed = round_trip(&data);
+            // Per chunk: its header, a literal and 3-byte references of at most `MAX_MATCH`.
+            let chunks = (len + MAX_CHUNK - 1) / MAX_CHUNK;
+            let references = (len + MAX_MATCH - 1) / MAX_MATCH + chunks;
+            assert!(compressed.len() <= chunks * (7 + 2) + references * 3, "{} bytes", compressed.len());
+        }
+    }
+
+    #[test]
+    fn known_blocks_are_decoded() {
+        // A literal run, then a back reference of 9 overlapping itself.
+        let block = b"ZV\x01\x00\x05\x00\x0a\x00a\xe0\x00\x00";
+        assert_eq!(decompress(block), b"aaaaaaaaaa");
+        assert_eq!(round_trip(b"aaaaaaaaaa"), block);
+        // A literal run, a back reference of 6 three bytes back, then an uncompressed chunk.
+        assert_eq!(decompress(b"ZV\x01\x00\x06\x00\x09\x02abc\x80\x02ZV\x00\x00\x03xyz"), b"abcabcabcxyz");
+    }
+}
@@ -1,47 +1,268 @@
 extern crate chrono;
+extern crate clap;
 extern crate crossbeam_channel;
+extern crate csv;
 extern crate fern;
+extern crate glob;
 extern crate itertools;
 #[macro_use] extern crate log;
 extern crate serde_json;
+extern crate walkdir;
 
 use itertools::Itertools;
 use serde_json::{Map, Value};
 
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io::{BufRead, BufReader};
+use std::io::BufRead;
+use std::mem;
 // use std::mem::size_of;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread;
 use std::time::Instant;
 
 extern crate dsp;
-use dsp::{Data, conf};
+use dsp::{Data, TIME_COLUMN, conf, segment};
+use dsp::avro::{AvroFile, Block};
+use dsp::compression::{self, Compression};
+use dsp::conf::{Command, InputFormat};
+use dsp::granularity::format_instant;
+#[cfg(feature = "parquet")]
+use dsp::parquet::ParquetFile;
+use dsp::spill::Spill;
 
-fn perform(filename: &str) {
-    info!("started `{}`", filename);
+fn write_segment(name: &str, (start, end): (i64, i64), segment: &Data) {
+    let mut output = conf::vals.output.clone();
+    output.push(name);
+    output.push(format!("{}_{}", format_instant(start), format_instant(end)));
+    fs::create_dir_all(&output).unwrap();
+    if let Err(e) = segment.write(&output, (start, end)) {
+        error!("could not write `{}`: {}", output.display(), e);
+        fs::remove_dir_all(&output).unwrap();
+        std::process::exit(1);
+    }
+}
+
+type CsvReader = csv::Reader<Box<dyn BufRead + Send>>;
+
+// Source of lines (json), of records (csv/tsv), the number of row groups (parquet)
+// or blocks (avro).
+enum Input {
+    Lines(Box<dyn BufRead + Send>),
+    Records(CsvReader),
+    #[cfg(feature = "parquet")]
+    RowGroups(usize),
+    Blocks(AvroFile),
+}
+
+// Work items of the worker threads.
+enum Chunk {
+    Lines(Vec<String>),
+    Records(Vec<csv::StringRecord>),
+    #[cfg(feature = "parquet")]
+    RowGroup(usize),
+    Block(Block),
+}
+
+// Skips `--skip-header-rows` and takes column names from the header
+// row, unless given with `--columns`.
+fn csv_reader(conf: &conf::Conf, filename: &str, file: Box<dyn BufRead + Send>) -> Result<(CsvReader, Vec<String>), String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(conf.delimiter())
+        .quote(conf.quote)
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(file);
+    let mut record = csv::StringRecord::new();
+    let mut next = |reader: &mut csv::Reader<_>| match reader.read_record(&mut record) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("`{}`: no header row", filename)),
+        Err(e) => Err(format!("`{}`: {}", filename, e)),
+    };
+    for _ in 0..conf.skip_header_rows {
+        next(&mut reader)?;
+    }
+    if !conf.columns.is_empty() {
+        return Ok((reader, conf.columns.clone()));
+    }
+    next(&mut reader)?;
+    Ok((reader, record.iter().map(|c| c.to_string()).collect()))
+}
+
+// Fields of configured dimensions (and the timestamp) are left as strings
+// to be coerced later, the others become numbers whenever they parse as such.
+// Empty fields are nulls.
+fn from_record(columns: &[String], record: &csv::StringRecord) -> Map<String, Value> {
+    columns.iter().zip(record.iter()).filter(|(_, field)| !field.is_empty()).map(|(column, field)| {
+        let value = if *column == conf::vals.timestamp_column || conf::vals.dimension_type(column).is_some() {
+            Value::String(field.to_string())
+        } else if let Ok(i) = field.parse::<i64>() {
+            Value::from(i)
+        } else if let Some(n) = field.parse().ok().and_then(serde_json::Number::from_f64) {
+            Value::Number(n)
+        } else {
+            Value::String(field.to_string())
+        };
+        (column.clone(), value)
+    }).collect()
+}
 
-    let file = fs::File::open(filename).unwrap();
+// Reads `filenames` into segments written to `<output>/<name>`.
+fn perform(name: &str, filenames: &[String]) {
+    info!("started `{}`", name);
 
     let mut instant = Instant::now();
 
     let mut data = Data::new();
 
+    // With `--max-rows-in-memory`, workers hand over their rows every
+    // (their share of) that many, and they get spilled to disk in runs.
+    let max_rows = conf::vals.max_rows_in_memory.map(|max| max.max(1));
+    let mut spill = max_rows.map(|max| Spill::new(name, max));
+
+    for filename in filenames {
+        read(filename, &mut data, &mut spill, max_rows);
+    }
+
+    if let Some(mut spill) = spill {
+        if data.rows() > 0 {
+            spill.add(data);
+        }
+
+        debug!("json `{:?}`", instant.elapsed());
+        instant = Instant::now();
+
+        for interval in spill.intervals() {
+            write_segment(name, interval, &spill.merged(interval));
+        }
+
+        debug!("merge `{:?}`", instant.elapsed());
+
+        info!("finished `{}`", name);
+        return;
+    }
+
+    data.preaggregate();
+
+    debug!("json `{:?}`", instant.elapsed());
+    instant = Instant::now();
+
+    data.sort();
+
+    debug!("sort `{:?}`", instant.elapsed());
+    instant = Instant::now();
+
+    data.rollup();
+
+    debug!("roll `{:?}`", instant.elapsed());
+    instant = Instant::now();
+
+    for (interval, segment) in data.split() {
+        write_segment(name, interval, &segment);
+    }
+
+    debug!("dump `{:?}`", instant.elapsed());
+
+    info!("finished `{}`", name);
+}
+
+// Appends rows of `filename` (`-` being stdin) to `data`, spilling it
+// whenever it grows past `max_rows`.
+fn read(filename: &str, data: &mut Data, spill: &mut Option<Spill>, max_rows: Option<usize>) {
+    info!("reading `{}`", filename);
+
+    let (input, columns) = match conf::vals.input_format {
+        InputFormat::JSON => (Input::Lines(compression::open(filename)), vec![]),
+        InputFormat::CSV | InputFormat::TSV => match csv_reader(&conf::vals, filename, compression::open(filename)) {
+            Ok((reader, columns)) => (Input::Records(reader), columns),
+            Err(e) => {
+                warn!("skipped {}", e);
+                return;
+            },
+        },
+        #[cfg(feature = "parquet")]
+        // Neither stdin nor compressed, see `inputs`.
+        InputFormat::Parquet => {
+            let parquet = ParquetFile::open(filename);
+            parquet.warn_unsupported();
+            (Input::RowGroups(parquet.row_groups()), vec![])
+        },
+        // Rejected by `Conf::load`.
+        #[cfg(not(feature = "parquet"))]
+        InputFormat::Parquet => unreachable!(),
+        InputFormat::Avro => match AvroFile::open(filename) {
+            Ok(avro) => (Input::Blocks(avro), vec![]),
+            Err(e) => {
+                warn!("skipped {}", e);
+                return;
+            },
+        },
+    };
+    let columns = Arc::new(columns);
+    let decoder = match &input {
+        Input::Blocks(avro) => Some(avro.decoder()),
+        _ => None,
+    };
+
     let (tx_ch, rx_ch): (
-        crossbeam_channel::Sender<Vec<String>>,
-        crossbeam_channel::Receiver<Vec<String>>
+        crossbeam_channel::Sender<Chunk>,
+        crossbeam_channel::Receiver<Chunk>
     ) = crossbeam_channel::bounded(conf::vals.threads * 4);
-    let (tx_res, rx_res) = crossbeam_channel::unbounded();
+    let (tx_res, rx_res) = crossbeam_channel::bounded(conf::vals.threads);
 
     for _ in 0..conf::vals.threads {
         let rx_ch = rx_ch.clone();
         let tx_res = tx_res.clone();
+        let columns = columns.clone();
+        let decoder = decoder.clone();
+        #[cfg(feature = "parquet")]
+        let filename = filename.to_string();
         thread::spawn(move || {
+            #[cfg(feature = "parquet")]
+            let mut parquet = None;
             let mut data = Data::new();
+            let mut skipped = 0;
+            let mut uncoerced: HashMap<String, usize> = HashMap::new();
+            let mut dropped: HashMap<String, usize> = HashMap::new();
             for chunk in rx_ch {
-                for line in chunk {
-                    let v: Map<String, Value> = serde_json::from_str(&line).unwrap();
+                let rows: Vec<Map<String, Value>> = match chunk {
+                    Chunk::Lines(lines) => lines.iter().map(|line| serde_json::from_str(line).unwrap()).collect(),
+                    Chunk::Records(records) => records.iter().map(|record| from_record(&columns, record)).collect(),
+                    Chunk::Block(block) => match decoder.as_ref().unwrap().rows(block) {
+                        Ok(rows) => rows,
+                        Err(e) => {
+                            warn!("skipped {}", e);
+                            vec![]
+                        },
+                    },
+                    // Row groups go straight into `data`, a column at a time.
+                    #[cfg(feature = "parquet")]
+                    Chunk::RowGroup(n) => {
+                        let parquet = parquet.get_or_insert_with(|| ParquetFile::open(&filename));
+                        skipped += parquet.read(n, &mut data, &mut uncoerced);
+                        vec![]
+                    },
+                };
+                for mut v in rows {
+                    let ts = v.remove(&conf::vals.timestamp_column)
+                        .and_then(|ts| conf::vals.timestamp_format.parse(&ts))
+                        .or(conf::vals.timestamp_missing)
+                        .filter(|ts| conf::vals.bucketable(*ts));
+                    match ts {
+                        Some(ts) => data.add_i(TIME_COLUMN.to_string(), ts),
+                        None => {
+                            skipped += 1;
+                            continue;
+                        },
+                    }
                     for (key, value) in v {
+                        if let Some(kind) = conf::vals.dimension_type(&key) {
+                            if !data.add_typed(key.clone(), value, kind) {
+                                *uncoerced.entry(key).or_insert(0) += 1;
+                            }
+                            continue;
+                        }
                         match value {
                             Value::Number(n) => {
                                 if n.is_i64() {
@@ -53,11 +274,33 @@ fn perform(filename: &str) {
                             Value::String(s) => {
                                 data.add_s(key, s);
                             },
+                            Value::Array(a) => {
+                                let count = data.add_array(key.clone(), a);
+                                if count > 0 {
+                                    *dropped.entry(key).or_insert(0) += count;
+                                }
+                            },
                             _ => (),
                         }
                     }
+                    data.next_row();
+                }
+                if let Some(max) = max_rows {
+                    if data.rows() >= (max / conf::vals.threads).max(1) {
+                        tx_res.send(mem::replace(&mut data, Data::new())).unwrap();
+                    }
                 }
             }
+            if skipped > 0 {
+                warn!("skipped {} rows without a valid `{}`", skipped, conf::vals.timestamp_column);
+            }
+            for (key, count) in uncoerced {
+                warn!("{} values of `{}` could not be coerced to {:?}, stored as nulls",
+                    count, key, conf::vals.dimension_type(&key).unwrap());
+            }
+            for (key, count) in dropped {
+                warn!("{} elements of `{}` arrays are not strings, numbers or booleans, dropped", count, key);
+            }
             tx_res.send(data).unwrap();
             drop(tx_res);
         });
@@ -66,34 +309,155 @@ fn perform(filename: &str) {
     drop(rx_ch);
     drop(tx_res);
 
-    for chunk_iter in &BufReader::new(file).lines().chunks(1000) {
-        let chunk: Vec<String> = chunk_iter.map(|c| c.unwrap()).collect();
-        tx_ch.send(chunk).unwrap();
-    }
-    drop(tx_ch);
+    // Lines (or records) are read in a thread of their own, so that parts can be taken
+    // (and spilled) as workers send them.
+    let reader = thread::spawn(move || match input {
+        Input::Lines(file) => for chunk_iter in &file.lines().chunks(1000) {
+            let chunk: Vec<String> = chunk_iter.map(|c| c.unwrap()).collect();
+            tx_ch.send(Chunk::Lines(chunk)).unwrap();
+        },
+        Input::Records(reader) => for chunk_iter in &reader.into_records().chunks(1000) {
+            let chunk: Vec<csv::StringRecord> = chunk_iter.map(|c| c.unwrap()).collect();
+            tx_ch.send(Chunk::Records(chunk)).unwrap();
+        },
+        #[cfg(feature = "parquet")]
+        Input::RowGroups(n) => for row_group in 0..n {
+            tx_ch.send(Chunk::RowGroup(row_group)).unwrap();
+        },
+        Input::Blocks(avro) => for block in avro {
+            tx_ch.send(Chunk::Block(block)).unwrap();
+        },
+    });
 
     for part in rx_res {
         data.append(part);
+        if let (Some(spill), Some(max)) = (spill.as_mut(), max_rows) {
+            if data.rows() >= max {
+                spill.add(mem::replace(data, Data::new()));
+            }
+        }
     }
+    reader.join().unwrap();
+}
+
+// Name of the directory holding `segment` (its datasource), as is.
+fn datasource(segment: &Path) -> String {
+    let dir = segment.canonicalize().unwrap();
+    dir.parent().unwrap().file_name().unwrap().to_string_lossy().into_owned()
+}
 
+fn merge(segments: &[PathBuf]) {
+    info!("started merging {} segments", segments.len());
+
+    let mut instant = Instant::now();
+
+    let mut data = Data::new();
+    let mut interval = (i64::MAX, i64::MIN);
+    for dir in segments {
+        let ((start, end), part) = segment::read(dir);
+        interval = (interval.0.min(start), interval.1.max(end));
+        data.append(part);
+    }
     data.preaggregate();
 
-    debug!("json `{:?}`", instant.elapsed());
+    debug!("read `{:?}`", instant.elapsed());
     instant = Instant::now();
 
     data.sort();
+    data.rollup();
 
-    debug!("sort `{:?}`", instant.elapsed());
+    debug!("roll `{:?}`", instant.elapsed());
     instant = Instant::now();
 
-    let mut output = conf::vals.output.clone();
-    output.push(PathBuf::from(filename).file_stem().unwrap());
-    fs::create_dir_all(&output).unwrap();
-    data.write(&output);
+    // Under the configured output, as `<output>/<name>/<interval>`, like the segments read.
+    write_segment(&datasource(&segments[0]), interval, &data);
 
     debug!("dump `{:?}`", instant.elapsed());
 
-    info!("finished `{}`", filename);
+    info!("finished merging");
+}
+
+// Files of the input format's extension, compressed or not (`*.json`, `*.json.gz`),
+// but for parquet, which cannot be.
+fn picked(conf: &conf::Conf, path: &Path) -> bool {
+    let path = match Compression::from_extension(path) {
+        Some(_) if conf.input_format == InputFormat::Parquet => return false,
+        Some(_) => Path::new(path.file_stem().unwrap()),
+        None => path,
+    };
+    path.extension().map_or(false, |ext| ext == conf.input_format.extension())
+}
+
+// Files to read, each with the name of its segments' directory: its stem, prefixed
+// with the path relative to the directory (or the fixed part of the glob pattern)
+// it was found through, e.g. `data/2026-10-*/*.json` gives `2026-10-01/events`.
+fn inputs(conf: &conf::Conf) -> Result<Vec<(String, String)>, String> {
+    let mut inputs = vec![];
+    for arg in &conf.files {
+        let path = Path::new(arg);
+        if arg == "-" {
+            inputs.push((arg.clone(), "stdin".to_string()));
+        } else if path.exists() {
+            let base = if path.is_dir() { path } else { path.parent().unwrap() };
+            found(conf, path, base, &mut inputs);
+        } else if arg.contains(&['*', '?', '['][..]) {
+            let base: PathBuf = path.components()
+                .take_while(|c| !c.as_os_str().to_string_lossy().contains(&['*', '?', '['][..]))
+                .collect();
+            let before = inputs.len();
+            for path in glob::glob(arg).unwrap() {
+                found(conf, &path.unwrap(), &base, &mut inputs);
+            }
+            if inputs.len() == before {
+                warn!("`{}` matches no files", arg);
+            }
+        } else {
+            return Err(format!("`{}` does not exist", arg));
+        }
+    }
+    // Parquet is read out of order, so it cannot be decompressed on the fly (nor read from stdin).
+    if conf.input_format == InputFormat::Parquet {
+        for (filename, _) in &inputs {
+            if filename == "-" {
+                return Err("parquet cannot be read from stdin".to_string());
+            }
+            if Compression::from_extension(Path::new(filename)).is_some() {
+                return Err(format!("`{}` has to be decompressed first", filename));
+            }
+        }
+    }
+    if conf.combine.is_none() {
+        let mut names = HashSet::new();
+        for (_, name) in &inputs {
+            if !names.insert(name) {
+                return Err(format!("more than one input would be written to `{}`, use `--combine`", name));
+            }
+        }
+    }
+    Ok(inputs)
+}
+
+// Directories are searched for files of the input format, just one level deep unless
+// `--recursive`. Files given (or matched) otherwise are taken whatever their name.
+fn found(conf: &conf::Conf, path: &Path, base: &Path, inputs: &mut Vec<(String, String)>) {
+    let name = |path: &Path| {
+        let relative = path.strip_prefix(base).unwrap_or(path);
+        relative.with_file_name(compression::stem(path.to_str().unwrap())).to_string_lossy().into_owned()
+    };
+    if !path.is_dir() {
+        inputs.push((path.to_string_lossy().into_owned(), name(path)));
+        return;
+    }
+    let walk = walkdir::WalkDir::new(path)
+        .min_depth(1)
+        .max_depth(if conf.recursive { usize::MAX } else { 1 })
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    for entry in walk {
+        let entry = entry.unwrap();
+        if entry.file_type().is_file() && picked(conf, entry.path()) {
+            inputs.push((entry.path().to_string_lossy().into_owned(), name(entry.path())));
+        }
+    }
 }
 
 fn main() {
@@ -109,22 +473,72 @@ fn main() {
     }
     logd.apply().unwrap();
 
-    let filemeta = fs::metadata(&conf::vals.file).unwrap();
-    if filemeta.is_file() {
-        perform(&conf::vals.file);
-    } else if filemeta.is_dir() {
-        for entry in fs::read_dir(&conf::vals.file).unwrap() {
-            let entry = entry.unwrap();
-
-            if let Ok(filemeta) = entry.metadata() {
-                if filemeta.is_file() {
-                    if let Some(ext) = entry.path().extension() {
-                        if ext == "json" {
-                            perform(entry.path().to_str().unwrap());
-                        }
-                    }
-                }
-            }
+    if let Some(Command::Merge{segments}) = &conf::vals.command {
+        merge(segments);
+        return;
+    }
+
+    let inputs = inputs(&conf::vals).unwrap_or_else(|e| {
+        clap::Error::with_description(&e, clap::ErrorKind::InvalidValue).exit()
+    });
+    if let Some(name) = &conf::vals.combine {
+        let filenames: Vec<String> = inputs.into_iter().map(|(filename, _)| filename).collect();
+        perform(name, &filenames);
+        return;
+    }
+    for (filename, name) in inputs {
+        perform(&name, &[filename]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate structopt;
+
+    use self::structopt::StructOpt;
+    use super::*;
+
+    #[test]
+    fn csv_files_without_a_header_are_errors() {
+        let conf = conf::Conf::from_iter(&["dsp", "--input-format", "csv", "-"]);
+        let file = |text: &str| -> Box<dyn BufRead + Send> { Box::new(std::io::Cursor::new(text.as_bytes().to_vec())) };
+        assert_eq!(csv_reader(&conf, "empty.csv", file("")).err(), Some("`empty.csv`: no header row".to_string()));
+
+        let (mut reader, columns) = csv_reader(&conf, "a.csv", file("a,b\n1,2\n")).unwrap();
+        assert_eq!(columns, ["a", "b"]);
+        assert_eq!(reader.records().count(), 1);
+    }
+
+    #[test]
+    fn mistaken_inputs_are_errors() {
+        let dir = std::env::temp_dir().join(format!("dsp-{}-inputs", std::process::id()));
+        for file in &["a/events.json", "b/events.json", "p/events.parquet.gz"] {
+            fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            fs::write(dir.join(file), b"").unwrap();
         }
+        let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        let inputs = |args: &[&str]| {
+            let args = ["dsp"].iter().chain(args).map(|arg| arg.to_string());
+            inputs(&conf::Conf::from_iter(args))
+        };
+
+        let both = inputs(&[&path("a"), &path("b")]);
+        assert_eq!(both.err(), Some("more than one input would be written to `events`, use `--combine`".to_string()));
+        assert_eq!(inputs(&["--combine", "events", &path("a"), &path("b")]).map(|inputs| inputs.len()), Ok(2));
+        assert_eq!(inputs(&[&path("c")]).err(), Some(format!("`{}` does not exist", path("c"))));
+        assert_eq!(inputs(&["--input-format", "parquet", "-"]).err(), Some("parquet cannot be read from stdin".to_string()));
+        let compressed = inputs(&["--input-format", "parquet", &path("p/events.parquet.gz")]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(compressed.err(), Some(format!("`{}` has to be decompressed first", path("p/events.parquet.gz"))));
+    }
+
+    #[test]
+    fn merged_segments_are_named_after_their_datasource_directory() {
+        let dir = std::env::temp_dir().join(format!("dsp-{}-merge", std::process::id()));
+        let segment = dir.join("events.v2").join("2020-01-01T00:00:00.000Z_2020-01-02T00:00:00.000Z");
+        fs::create_dir_all(&segment).unwrap();
+        let name = datasource(&segment.join(".").join("..").join(segment.file_name().unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(name, "events.v2");
     }
 }
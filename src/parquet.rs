@@ -0,0 +1,302 @@
+extern crate parquet;
+
+use serde_json::{Number, Value};
+use self::parquet::basic::{ConvertedType, LogicalType, Repetition, TimeUnit};
+use self::parquet::column::reader::{ColumnReader, ColumnReaderImpl};
+use self::parquet::data_type::DataType;
+use self::parquet::file::reader::{FileReader, RowGroupReader, SerializedFileReader};
+use self::parquet::schema::types::ColumnDescriptor;
+
+use std::collections::HashMap;
+use std::fs::File;
+
+use conf;
+use interner::IS;
+use {Data, ValVec, TIME_COLUMN};
+
+/// Parquet file read a row group at a time, and each row group a column at a time.
+/// Top level columns of primitive types and lists of them are read,
+/// the rest (nested groups, maps, lists of those) are skipped.
+pub struct ParquetFile {
+    reader: SerializedFileReader<File>,
+    columns: Vec<Column>,
+    unsupported: Vec<String>,
+}
+
+struct Column {
+    leaf: usize,
+    name: String,
+    list: bool,
+    // Whether a list (as opposed to its elements) can be null.
+    nullable: bool,
+}
+
+// Value of a leaf column, converted from its physical (and logical) type.
+enum Cell {
+    Long(i64),
+    Double(f64),
+    String(String),
+    // Milliseconds since epoch, from timestamps and dates.
+    Timestamp(i64),
+}
+
+impl Cell {
+    fn into_value(self) -> Value {
+        match self {
+            Cell::Long(v) | Cell::Timestamp(v) => Value::from(v),
+            Cell::Double(v) => Number::from_f64(v).map(Value::Number).unwrap_or(Value::Null),
+            Cell::String(v) => Value::String(v),
+        }
+    }
+
+    fn into_string(self) -> String {
+        match self {
+            Cell::Long(v) | Cell::Timestamp(v) => v.to_string(),
+            Cell::Double(v) => v.to_string(),
+            Cell::String(v) => v,
+        }
+    }
+}
+
+impl ParquetFile {
+    pub fn open(path: &str) -> Self {
+        let reader = SerializedFileReader::new(File::open(path).unwrap())
+            .unwrap_or_else(|e| panic!("`{}` is not a valid parquet file: {}", path, e));
+        let mut columns: Vec<Column> = vec![];
+        let mut unsupported = vec![];
+        {
+            let schema = reader.metadata().file_metadata().schema_descr();
+            for leaf in 0..schema.num_columns() {
+                let descr = schema.column(leaf);
+                let root = schema.get_column_root(leaf).get_basic_info();
+                let path = descr.path().parts();
+                if unsupported.contains(&path[0]) {
+                    continue;
+                }
+                let is_list = root.converted_type() == ConvertedType::LIST || root.logical_type() == Some(LogicalType::List);
+                let list = match (descr.max_rep_level(), path.len()) {
+                    (0, 1) => false,
+                    // `repeated <type> name`, `name (LIST) { repeated <type> element }`
+                    // or `name (LIST) { repeated group list { <type> element } }`
+                    (1, 1) | (1, 2) | (1, 3) if path.len() == 1 || is_list => true,
+                    _ => {
+                        unsupported.push(path[0].clone());
+                        continue;
+                    },
+                };
+                if columns.iter().any(|c| c.name == path[0]) {
+                    // Lists of groups have more leaves than one.
+                    columns.retain(|c| c.name != path[0]);
+                    unsupported.push(path[0].clone());
+                    continue;
+                }
+                let nullable = root.has_repetition() && root.repetition() == Repetition::OPTIONAL;
+                columns.push(Column{leaf, name: path[0].clone(), list, nullable});
+            }
+        }
+        ParquetFile{reader, columns, unsupported}
+    }
+
+    pub fn row_groups(&self) -> usize {
+        self.reader.metadata().num_row_groups()
+    }
+
+    pub fn warn_unsupported(&self) {
+        for name in &self.unsupported {
+            warn!("column `{}` is of an unsupported type, skipping", name);
+        }
+    }
+
+    /// Appends rows of the `n`th row group to `data`, returning the number of
+    /// rows skipped for lack of a (valid) timestamp. Values that could not be
+    /// coerced to their dimension's type are counted in `uncoerced`.
+    pub fn read(&self, n: usize, data: &mut Data, uncoerced: &mut HashMap<String, usize>) -> usize {
+        let row_group = self.reader.get_row_group(n).unwrap();
+        let rows = row_group.metadata().num_rows() as usize;
+
+        let mut timestamps = vec![conf::vals.timestamp_missing; rows];
+        let mut scalars = vec![];
+        let mut lists = vec![];
+        for column in &self.columns {
+            if column.list {
+                lists.push((&column.name, read_lists(&*row_group, column, rows)));
+            } else if column.name == conf::vals.timestamp_column {
+                for (ts, cell) in timestamps.iter_mut().zip(read_cells(&*row_group, column, rows)) {
+                    let parsed = match cell {
+                        Some(Cell::Timestamp(t)) => Some(t),
+                        Some(cell) => conf::vals.timestamp_format.parse(&cell.into_value()),
+                        None => None,
+                    };
+                    *ts = parsed.or(*ts);
+                }
+            } else {
+                scalars.push((&column.name, read_cells(&*row_group, column, rows)));
+            }
+        }
+
+        // Rows without a timestamp (that can be bucketed) are dropped from all the columns.
+        for ts in timestamps.iter_mut() {
+            *ts = ts.filter(|ts| conf::vals.bucketable(*ts));
+        }
+        let keep: Vec<bool> = timestamps.iter().map(|ts| ts.is_some()).collect();
+        let skipped = keep.iter().filter(|k| !**k).count();
+
+        let mut part = Data::new();
+        let mut ts = ValVec::Integer(vec![], vec![]);
+        for t in timestamps.into_iter().flatten() {
+            ts.push_i(t);
+        }
+        part.add_column(TIME_COLUMN.to_string(), ts);
+        for (name, mut cells) in scalars {
+            retain(&mut cells, &keep);
+            let kind = conf::vals.dimension_type(name);
+            if let Some(column) = to_column(cells, kind, uncoerced.entry(name.clone()).or_insert(0)) {
+                part.add_column(name.clone(), column);
+            }
+        }
+        for (name, mut values) in lists {
+            retain(&mut values, &keep);
+            let column = match conf::vals.dimension_type(name) {
+                None => {
+                    let mut is = IS::new_multi();
+                    for value in values {
+                        match value {
+                            Some(value) => is.add_ms(value.into_iter().map(Cell::into_string).collect()),
+                            None => is.add_null(),
+                        }
+                    }
+                    ValVec::MultiIndexedString(is)
+                },
+                Some(kind) => {
+                    let failed = uncoerced.entry(name.clone()).or_insert(0);
+                    let mut column = ValVec::typed(kind);
+                    for value in values {
+                        let value = value.map(|v| Value::Array(v.into_iter().map(Cell::into_value).collect()));
+                        if !column.push_typed(value.unwrap_or(Value::Null), kind) {
+                            *failed += 1;
+                        }
+                    }
+                    column
+                },
+            };
+            part.add_column(name.clone(), column);
+        }
+        uncoerced.retain(|_, count| *count > 0);
+        data.append(part);
+        skipped
+    }
+}
+
+// A column is made of its values' type, unless it is a dimension with a type
+// configured. Columns of nulls only are left out, as missing JSON fields are.
+fn to_column(cells: Vec<Option<Cell>>, kind: Option<conf::DimensionType>, failed: &mut usize) -> Option<ValVec> {
+    let mut column = match (kind, cells.iter().flatten().next()?) {
+        (Some(kind), _) => ValVec::typed(kind),
+        (None, Cell::Long(_)) | (None, Cell::Timestamp(_)) => ValVec::Integer(vec![], vec![]),
+        (None, Cell::Double(_)) => ValVec::Float(vec![], vec![]),
+        (None, Cell::String(_)) => ValVec::IndexedString(IS::new()),
+    };
+    for cell in cells {
+        match (kind, cell) {
+            (_, None) => column.push_null(),
+            (None, Some(Cell::Long(v))) | (None, Some(Cell::Timestamp(v))) => column.push_i(v),
+            (None, Some(Cell::Double(v))) => column.push_f(v),
+            (None, Some(Cell::String(v))) => column.push_s(v),
+            (Some(kind), Some(cell)) => if !column.push_typed(cell.into_value(), kind) {
+                *failed += 1;
+            },
+        }
+    }
+    Some(column)
+}
+
+fn retain<T>(values: &mut Vec<T>, keep: &[bool]) {
+    let mut keep = keep.iter();
+    values.retain(|_| *keep.next().unwrap());
+}
+
+fn read_cells(row_group: &dyn RowGroupReader, column: &Column, rows: usize) -> Vec<Option<Cell>> {
+    let (cells, def, _) = read_leaf(row_group, column.leaf, rows);
+    let max_def = row_group.metadata().column(column.leaf).column_descr().max_def_level();
+    if max_def == 0 {
+        return cells.into_iter().map(Some).collect();
+    }
+    let mut cells = cells.into_iter();
+    def.iter().map(|d| if *d == max_def { cells.next() } else { None }).collect()
+}
+
+fn read_lists(row_group: &dyn RowGroupReader, column: &Column, rows: usize) -> Vec<Option<Vec<Cell>>> {
+    let (cells, def, rep) = read_leaf(row_group, column.leaf, rows);
+    let max_def = row_group.metadata().column(column.leaf).column_descr().max_def_level();
+    let mut cells = cells.into_iter();
+    let mut lists: Vec<Option<Vec<Cell>>> = Vec::with_capacity(rows);
+    for (d, r) in def.iter().zip(rep.iter()) {
+        if *r == 0 {
+            lists.push(if *d == 0 && column.nullable { None } else { Some(vec![]) });
+        }
+        if *d == max_def {
+            if let Some(Some(list)) = lists.last_mut() {
+                list.push(cells.next().unwrap());
+            }
+        }
+    }
+    lists
+}
+
+// Non-null values of a leaf column, with its definition and repetition levels.
+fn read_leaf(row_group: &dyn RowGroupReader, leaf: usize, rows: usize) -> (Vec<Cell>, Vec<i16>, Vec<i16>) {
+    let descr = row_group.metadata().column(leaf).column_descr_ptr();
+    let descr: &ColumnDescriptor = &descr;
+    let decimal = match (descr.logical_type(), descr.converted_type()) {
+        (Some(LogicalType::Decimal{scale, ..}), _) => Some(10f64.powi(scale)),
+        (_, ConvertedType::DECIMAL) => Some(10f64.powi(descr.type_scale())),
+        _ => None,
+    };
+    // Dates and timestamps into milliseconds: times and by.
+    let millis = match (descr.logical_type(), descr.converted_type()) {
+        (Some(LogicalType::Timestamp{unit: TimeUnit::MILLIS(_), ..}), _) | (_, ConvertedType::TIMESTAMP_MILLIS) => Some((1, 1)),
+        (Some(LogicalType::Timestamp{unit: TimeUnit::MICROS(_), ..}), _) | (_, ConvertedType::TIMESTAMP_MICROS) => Some((1, 1_000)),
+        (Some(LogicalType::Timestamp{unit: TimeUnit::NANOS(_), ..}), _) => Some((1, 1_000_000)),
+        (Some(LogicalType::Date), _) | (_, ConvertedType::DATE) => Some((86_400_000, 1)),
+        _ => None,
+    };
+    let long = |v: i64| match (decimal, millis) {
+        (Some(scale), _) => Cell::Double(v as f64 / scale),
+        (_, Some((times, by))) => Cell::Timestamp((v * times).div_euclid(by)),
+        (None, None) => Cell::Long(v),
+    };
+    let bytes = |v: &[u8]| match decimal {
+        // Big-endian two's complement unscaled value.
+        Some(scale) => {
+            let unscaled = v.iter().fold(if v.first().map_or(false, |b| b & 0x80 != 0) { -1i128 } else { 0 }, |n, b| n << 8 | i128::from(*b));
+            Cell::Double(unscaled as f64 / scale)
+        },
+        None => Cell::String(String::from_utf8_lossy(v).into_owned()),
+    };
+
+    match row_group.get_column_reader(leaf).unwrap() {
+        ColumnReader::BoolColumnReader(r) => read_values(r, rows, |v| Cell::String(v.to_string())),
+        ColumnReader::Int32ColumnReader(r) => read_values(r, rows, |v| long(i64::from(v))),
+        ColumnReader::Int64ColumnReader(r) => read_values(r, rows, long),
+        ColumnReader::Int96ColumnReader(r) => read_values(r, rows, |v| Cell::Timestamp(v.to_nanos().div_euclid(1_000_000))),
+        ColumnReader::FloatColumnReader(r) => read_values(r, rows, |v| Cell::Double(f64::from(v))),
+        ColumnReader::DoubleColumnReader(r) => read_values(r, rows, Cell::Double),
+        ColumnReader::ByteArrayColumnReader(r) => read_values(r, rows, |v| bytes(v.data())),
+        ColumnReader::FixedLenByteArrayColumnReader(r) => read_values(r, rows, |v| bytes(v.data())),
+    }
+}
+
+fn read_values<T: DataType, F: Fn(T::T) -> Cell>(mut reader: ColumnReaderImpl<T>, rows: usize, f: F) -> (Vec<Cell>, Vec<i16>, Vec<i16>) {
+    let mut values = Vec::with_capacity(rows);
+    let mut def = Vec::with_capacity(rows);
+    let mut rep = Vec::with_capacity(rows);
+    let mut read = 0;
+    while read < rows {
+        let (records, _, _) = reader.read_records(rows - read, Some(&mut def), Some(&mut rep), &mut values).unwrap();
+        if records == 0 {
+            break;
+        }
+        read += records;
+    }
+    (values.into_iter().map(f).collect(), def, rep)
+}
@@ -0,0 +1,433 @@
+use byteorder::{BE, LE, ByteOrder, ReadBytesExt};
+use clap::ArgMatches;
+use serde_json::Value;
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+use bitmap;
+use conf::{BitmapType, Conf, Dimension, DimensionType, Metric};
+use interner::IS;
+use longs;
+use lzf;
+use smoosh::Smooshed;
+use spec;
+use {Data, ValVec, TIME_COLUMN};
+
+type Reader<'a> = Cursor<&'a [u8]>;
+
+/// Fills `conf` from the segments' own `index.drd` and `metadata.drd`:
+/// dimensions and metrics of all of them (in order of appearance), rollup
+/// and granularity of the first one. Options given explicitly on the command line win.
+pub fn apply(conf: &mut Conf, matches: &ArgMatches, dirs: &[PathBuf]) -> Result<(), String> {
+    let given = |name: &str| matches.occurrences_of(name) > 0;
+
+    let mut dimensions: Vec<Dimension> = vec![];
+    let mut metrics: Vec<Metric> = vec![];
+    for (n, dir) in dirs.iter().enumerate() {
+        let segment = Segment::open(dir)?;
+        for name in &segment.dimensions {
+            if !dimensions.iter().any(|d| &d.name == name) {
+                dimensions.push(Dimension{name: name.clone(), kind: segment.kind(name)?});
+            }
+        }
+        for aggregator in segment.metadata["aggregators"].as_array().unwrap_or(&vec![]) {
+            let metric = spec::parse_metric(aggregator)?;
+            if !metrics.iter().any(|m| m.name == metric.name) {
+                metrics.push(metric);
+            }
+        }
+        if n > 0 {
+            continue;
+        }
+
+        let timestamp = &segment.metadata["timestampSpec"];
+        if let (false, Some(column)) = (given("timestamp_column"), timestamp["column"].as_str()) {
+            conf.timestamp_column = column.to_string();
+        }
+        if let (false, Some(format)) = (given("timestamp_format"), timestamp["format"].as_str()) {
+            conf.timestamp_format = format.parse()?;
+        }
+        if let (false, Some(rollup)) = (given("no_rollup"), segment.metadata["rollup"].as_bool()) {
+            conf.no_rollup = !rollup;
+        }
+        match (given("query_granularity"), &segment.metadata["queryGranularity"]) {
+            (false, Value::Null) | (true, _) => (),
+            (false, query) => conf.query_granularity = spec::parse_granularity(query)?,
+        }
+    }
+    if !given("dimensions") {
+        conf.dimensions = dimensions;
+    }
+    if !given("metrics") {
+        conf.metrics = metrics;
+    }
+    Ok(())
+}
+
+/// Reads a segment written by dsp back, with rows in the order they were written.
+pub fn read(dir: &Path) -> ((i64, i64), Data) {
+    let segment = Segment::open(dir).unwrap_or_else(|e| panic!("{}", e));
+    let mut columns = HashMap::new();
+    columns.insert(TIME_COLUMN.to_string(), segment.column(TIME_COLUMN));
+    for name in &segment.columns {
+        columns.insert(name.clone(), segment.column(name));
+    }
+    let rows = columns[TIME_COLUMN].len();
+    (segment.interval, Data{columns, rows})
+}
+
+struct Segment {
+    dir: PathBuf,
+    smooshed: Smooshed,
+    // All the columns but `__time`, metrics first.
+    columns: Vec<String>,
+    dimensions: Vec<String>,
+    interval: (i64, i64),
+    metadata: Value,
+}
+
+impl Segment {
+    fn open(dir: &Path) -> Result<Self, String> {
+        if dir.join("index.zip").exists() {
+            return Err(format!("`{}` is zipped, it has to be unzipped first", dir.display()));
+        }
+        let smooshed = Smooshed::open(dir)?;
+        let index = smooshed.get("index.drd").ok_or(format!("`{}` has no `index.drd`", dir.display()))?;
+        let mut reader = Cursor::new(&index[..]);
+        let columns = read_indexed(&mut reader, &smooshed).into_iter().map(utf8).collect();
+        let dimensions = read_indexed(&mut reader, &smooshed).into_iter().map(utf8).collect();
+        let interval = (reader.read_i64::<BE>().unwrap(), reader.read_i64::<BE>().unwrap());
+        let metadata = match smooshed.get("metadata.drd") {
+            Some(metadata) => serde_json::from_slice(&metadata)
+                .map_err(|e| format!("invalid `metadata.drd` in `{}`: {}", dir.display(), e))?,
+            None => Value::Null,
+        };
+        Ok(Segment{dir: dir.to_path_buf(), smooshed, columns, dimensions, interval, metadata})
+    }
+
+    fn descriptor(&self, name: &str) -> Result<(Value, Vec<u8>), String> {
+        let data = self.smooshed.get(name)
+            .ok_or(format!("`{}` has no column `{}`", self.dir.display(), name))?;
+        let len = BE::read_u32(&data) as usize;
+        let descriptor = serde_json::from_slice(&data[4..4 + len])
+            .map_err(|e| format!("invalid descriptor of `{}` in `{}`: {}", name, self.dir.display(), e))?;
+        Ok((descriptor, data[4 + len..].to_vec()))
+    }
+
+    fn kind(&self, name: &str) -> Result<DimensionType, String> {
+        let (descriptor, _) = self.descriptor(name)?;
+        descriptor["valueType"].as_str().unwrap_or("").parse()
+    }
+
+    fn column(&self, name: &str) -> ValVec {
+        let (descriptor, data) = self.descriptor(name).unwrap_or_else(|e| panic!("{}", e));
+        let part = &descriptor["parts"][0];
+        let bitmap_type = match part.pointer("/bitmapSerdeFactory/type").and_then(|t| t.as_str()) {
+            Some("roaring") => BitmapType::Roaring,
+            _ => BitmapType::Concise,
+        };
+        let mut reader = Cursor::new(&data[..]);
+        let kind = part["type"].as_str().unwrap_or("");
+        match kind {
+            "stringDictionary" => {
+                let multi = descriptor["hasMultipleValues"].as_bool().unwrap_or(false);
+                let is = read_strings(&mut reader, &self.smooshed, multi);
+                if multi { ValVec::MultiIndexedString(is) } else { ValVec::IndexedString(is) }
+            },
+            "long" | "longV2" | "float" | "floatV2" | "double" | "doubleV2" => {
+                let nullable = kind.ends_with("V2");
+                if nullable {
+                    reader.read_u32::<BE>().unwrap(); // offset of the null bitmap
+                }
+                let (total, size_per, encoding, blocks) = read_blocks(&mut reader, &self.smooshed);
+
+                let mut nulls = vec![false; total];
+                if nullable && (reader.position() as usize) < data.len() {
+                    let size = reader.read_u32::<BE>().unwrap() as usize;
+                    for row in bitmap::rows(bitmap_type, take(&mut reader, size)) {
+                        nulls[row] = true;
+                    }
+                }
+
+                let counts = (0..blocks.len()).map(|n| size_per.min(total - n * size_per));
+                match &kind[..kind.len() - nullable as usize * 2] {
+                    "long" => {
+                        let mut values = Vec::with_capacity(total);
+                        for (block, count) in blocks.iter().zip(counts) {
+                            match &encoding {
+                                Some(encoding) => encoding.read_block(block, count, &mut values),
+                                None => values.extend(block.chunks(8).take(count).map(LE::read_i64)),
+                            }
+                        }
+                        ValVec::Integer(values, nulls)
+                    },
+                    "float" => ValVec::Float32(blocks.iter().zip(counts)
+                        .flat_map(|(block, count)| block.chunks(4).take(count).map(LE::read_f32))
+                        .collect(), nulls),
+                    _ => ValVec::Float(blocks.iter().zip(counts)
+                        .flat_map(|(block, count)| block.chunks(8).take(count).map(LE::read_f64))
+                        .collect(), nulls),
+                }
+            },
+            _ => panic!("column `{}` in `{}` is of unsupported type `{}`", name, self.dir.display(), kind),
+        }
+    }
+}
+
+// Next `len` bytes of `reader`.
+fn take<'a>(reader: &mut Reader<'a>, len: usize) -> &'a [u8] {
+    let data: &'a [u8] = reader.get_ref();
+    let start = reader.position() as usize;
+    reader.set_position((start + len) as u64);
+    &data[start..start + len]
+}
+
+fn utf8(value: Option<Vec<u8>>) -> String {
+    String::from_utf8(value.unwrap_or_default()).unwrap()
+}
+
+// Reverses `compress`, `compression` being the `CompressionStrategy` id.
+fn decompress(data: &[u8], compression: u8) -> Vec<u8> {
+    match compression {
+        0xff => data.to_vec(),
+        0x0 => lzf::decompress(data),
+        0x1 => lz4::block::decompress(data, Some(0x10000)).unwrap(),
+        0x2 => zstd::bulk::decompress(data, 0x10000).unwrap(),
+        _ => panic!("unsupported compression {:#x}", compression),
+    }
+}
+
+// GenericIndexed values without their nullness markers, `None` being null.
+fn read_indexed(reader: &mut Reader, smooshed: &Smooshed) -> Vec<Option<Vec<u8>>> {
+    let value = |data: &[u8]| match data {
+        [0xff, 0xff, 0xff, 0xff] => None,
+        _ => Some(data[4..].to_vec()),
+    };
+    match reader.read_u8().unwrap() {
+        1 => {
+            reader.read_u8().unwrap(); // reverse lookup
+            let size = reader.read_u32::<BE>().unwrap() as usize;
+            let mut indexed = Cursor::new(take(reader, size));
+            let count = indexed.read_u32::<BE>().unwrap() as usize;
+            let ends: Vec<usize> = (0..count).map(|_| indexed.read_u32::<BE>().unwrap() as usize).collect();
+            let values = take(&mut indexed, size - 4 - count * 4);
+            let mut start = 0;
+            ends.into_iter().map(|end| {
+                let v = value(&values[start..end]);
+                start = end;
+                v
+            }).collect()
+        },
+        2 => {
+            reader.read_u8().unwrap(); // reverse lookup
+            let per_file = 1 << reader.read_u32::<BE>().unwrap();
+            let count = reader.read_u32::<BE>().unwrap() as usize;
+            let len = reader.read_u32::<BE>().unwrap() as usize;
+            let name = String::from_utf8(take(reader, len).to_vec()).unwrap();
+
+            let entry = |name: String| smooshed.get(&name).unwrap_or_else(|| panic!("no `{}` entry", name));
+            let header = entry(format!("{}_header", name));
+            let mut file = vec![];
+            let mut start = 0;
+            (0..count).map(|i| {
+                if i % per_file == 0 {
+                    file = entry(format!("{}_value_{}", name, i / per_file));
+                    start = 0;
+                }
+                let end = BE::read_u32(&header[i * 4..]) as usize;
+                let v = value(&file[start..end]);
+                start = end;
+                v
+            }).collect()
+        },
+        version => panic!("unsupported GenericIndexed version {}", version),
+    }
+}
+
+// Header of compressed numeric columns and their decompressed blocks:
+// number of values, values per block and, for longs, their encoding.
+fn read_blocks(reader: &mut Reader, smooshed: &Smooshed) -> (usize, usize, Option<longs::Encoding>, Vec<Vec<u8>>) {
+    reader.read_u8().unwrap(); // version
+    let total = reader.read_u32::<BE>().unwrap() as usize;
+    let size_per = reader.read_u32::<BE>().unwrap() as usize;
+    let mut compression = reader.read_u8().unwrap();
+    let mut encoding = None;
+    // CompressionFactory.hasEncodingFlag
+    if (compression as i8) < -100 {
+        compression = (compression as i8).wrapping_add(126) as u8;
+        encoding = Some(longs::Encoding::read_header(reader));
+    }
+    let blocks = read_indexed(reader, smooshed).into_iter()
+        .map(|block| decompress(&block.unwrap_or_default(), compression))
+        .collect();
+    (total, size_per, encoding, blocks)
+}
+
+fn read_strings(reader: &mut Reader, smooshed: &Smooshed, multi: bool) -> IS {
+    let version = reader.read_u8().unwrap();
+    if version == 2 {
+        reader.read_u32::<BE>().unwrap(); // flags
+    }
+    let keys = match reader.get_ref()[reader.position() as usize] {
+        0x7f => read_front_coded(reader),
+        _ => read_indexed(reader, smooshed).into_iter()
+            .map(|k| k.map(|k| String::from_utf8(k).unwrap()))
+            .collect(),
+    };
+    let (indexes, ends) = match version {
+        0 => {
+            reader.read_u8().unwrap(); // version
+            let num_bytes = reader.read_u8().unwrap() as usize;
+            let size = reader.read_u32::<BE>().unwrap() as usize;
+            let values = take(reader, size - (4 - num_bytes));
+            reader.set_position(reader.position() + 4 - num_bytes as u64);
+            (values.chunks(num_bytes).map(|v| BE::read_uint(v, num_bytes) as usize).collect(), None)
+        },
+        1 => {
+            reader.read_u8().unwrap(); // version
+            let num_bytes = reader.read_u8().unwrap() as usize;
+            reader.read_u32::<BE>().unwrap(); // size
+            let rows = reader.read_u32::<BE>().unwrap() as usize;
+            let ends: Vec<usize> = (0..rows)
+                .map(|_| reader.read_u32::<BE>().unwrap() as usize / num_bytes)
+                .collect();
+            let values = take(reader, ends.last().cloned().unwrap_or(0) * num_bytes);
+            reader.set_position(reader.position() + 4 - num_bytes as u64);
+            (values.chunks(num_bytes).map(|v| BE::read_uint(v, num_bytes) as usize).collect(), Some(ends))
+        },
+        _ if multi => {
+            reader.read_u8().unwrap(); // version (V3CompressedVSizeColumnarMultiIntsSupplier)
+            let offsets = read_compressed_ints(reader, smooshed, Some(4));
+            let indexes = read_compressed_ints(reader, smooshed, None);
+            (indexes, Some(offsets[1..].to_vec()))
+        },
+        _ => (read_compressed_ints(reader, smooshed, None), None),
+    };
+    IS::from_ids(keys, indexes, ends)
+}
+
+// CompressedColumnarIntsSupplier (with `num_bytes` of 4) or
+// CompressedVSizeColumnarIntsSupplier (with `num_bytes` in the header).
+fn read_compressed_ints(reader: &mut Reader, smooshed: &Smooshed, num_bytes: Option<usize>) -> Vec<usize> {
+    reader.read_u8().unwrap(); // version
+    let num_bytes = num_bytes.unwrap_or_else(|| reader.read_u8().unwrap() as usize);
+    let length = reader.read_u32::<BE>().unwrap() as usize;
+    reader.read_u32::<BE>().unwrap(); // values per block
+    let compression = reader.read_u8().unwrap();
+    let mut values = Vec::with_capacity(length);
+    for block in read_indexed(reader, smooshed) {
+        let block = decompress(&block.unwrap_or_default(), compression);
+        let count = (block.len() / num_bytes).min(length - values.len());
+        values.extend(block.chunks(num_bytes).take(count).map(|v| LE::read_uint(v, num_bytes) as usize));
+    }
+    values
+}
+
+// FrontCodedIndexed (V0), as written by `interner::write_front_coded`.
+fn read_front_coded(reader: &mut Reader) -> Vec<Option<String>> {
+    reader.read_u8().unwrap(); // EncodedStringDictionaryWriter.VERSION
+    reader.read_u8().unwrap(); // StringEncodingStrategy.FRONT_CODED_ID
+    reader.read_u8().unwrap(); // FrontCodedIndexed.V0
+    let bucket_size = reader.read_u8().unwrap() as usize;
+    let has_null = reader.read_u8().unwrap() != 0;
+    let count = read_vbyte(reader);
+    let size = read_vbyte(reader);
+    let num_buckets = (count + bucket_size - 1) / bucket_size;
+    let mut buckets = Cursor::new(&take(reader, size)[num_buckets.saturating_sub(1) * 4..]);
+
+    let mut keys = Vec::with_capacity(count + has_null as usize);
+    if has_null {
+        keys.push(None);
+    }
+    for bucket in 0..num_buckets {
+        let len = read_vbyte(&mut buckets);
+        let first = take(&mut buckets, len);
+        keys.push(Some(String::from_utf8(first.to_vec()).unwrap()));
+        for _ in 1..bucket_size.min(count - bucket * bucket_size) {
+            let prefix = read_vbyte(&mut buckets);
+            let len = read_vbyte(&mut buckets);
+            let mut value = first[..prefix].to_vec();
+            value.extend_from_slice(take(&mut buckets, len));
+            keys.push(Some(String::from_utf8(value).unwrap()));
+        }
+    }
+    keys
+}
+
+fn read_vbyte(reader: &mut Reader) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let b = reader.read_u8().unwrap() as usize;
+        value |= (b & 0x7f) << shift;
+        if b & 0x80 != 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use conf;
+    use smoosh::Smoosher;
+    use std::fs;
+    use META_TYPES;
+
+    // Writes `column` into a smoosh of `chunk_size` chunks, returning its entries' sizes
+    // and the column read back.
+    fn smooshed(name: &str, column: &IS, chunk_size: usize) -> Result<(Vec<(String, usize)>, IS), String> {
+        let dir = std::env::temp_dir().join(format!("dsp-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        let mut smoosher = Smoosher::new(dir.clone(), chunk_size);
+        let written = column.write(&mut smoosher, name, &META_TYPES["string"], conf::Compression::LZ4);
+        smoosher.finish(&mut fs::File::create(dir.join("meta.smoosh")).unwrap());
+
+        let result = written.map(|_| {
+            let meta = fs::read_to_string(dir.join("meta.smoosh")).unwrap();
+            let sizes = meta.lines().skip(1).map(|line| {
+                let fields: Vec<&str> = line.split(',').collect();
+                (fields[0].to_string(), fields[3].parse::<usize>().unwrap() - fields[2].parse::<usize>().unwrap())
+            }).collect();
+            let smooshed = Smooshed::open(&dir).unwrap();
+            let data = smooshed.get(name).unwrap();
+            let len = BE::read_u32(&data) as usize;
+            (sizes, read_strings(&mut Cursor::new(&data[4 + len..]), &smooshed, false))
+        });
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    #[test]
+    fn columns_larger_than_a_smoosh_chunk_are_split() {
+        let chunk_size = 4096;
+        // Dictionary and bitmaps each within a chunk but not together, then both over one.
+        for (name, keys) in &[("split-together", 150), ("split-apart", 600)] {
+            let mut column = IS::new();
+            for row in 0..keys * 4 {
+                column.add_s(format!("value-{:010}", row * 7 % keys));
+            }
+            column.sort();
+
+            let (sizes, read) = smooshed(name, &column, chunk_size).unwrap();
+            for (entry, size) in &sizes {
+                assert!(*size <= chunk_size, "`{}` has {} bytes", entry, size);
+            }
+            assert!(sizes.iter().any(|(entry, _)| *entry == format!("{}_header", name)));
+            assert!(sizes.iter().any(|(entry, _)| *entry == format!("{}.inverted_header", name)));
+            assert_eq!(read.values(), column.values());
+        }
+    }
+
+    #[test]
+    fn values_larger_than_a_smoosh_chunk_are_errors() {
+        let mut column = IS::new();
+        column.add_s("v".repeat(5000));
+        column.sort();
+        let error = smooshed("too-large", &column, 4096).err().unwrap();
+        assert!(error.contains("single value over"), "{}", error);
+    }
+}
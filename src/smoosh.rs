@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub fn chunk_name(n: usize) -> String {
+    format!("{:05}.smoosh", n)
+}
+
+/// FileSmoosher: named entries are streamed to `00000.smoosh`, `00001.smoosh`, ...
+/// in `dir`, moving on to the next chunk whenever an entry does not fit in
+/// `max_chunk_size`.
+pub struct Smoosher {
+    dir: PathBuf,
+    chunk: BufWriter<File>,
+    num_chunks: usize,
+    max_chunk_size: usize,
+    // name, chunk, start, end
+    entries: Vec<(String, usize, usize, usize)>,
+}
+
+impl Smoosher {
+    pub fn new(dir: PathBuf, max_chunk_size: usize) -> Self {
+        let chunk = open_chunk(&dir, 0);
+        Smoosher{dir, chunk, num_chunks: 1, max_chunk_size, entries: vec![]}
+    }
+
+    pub fn max_chunk_size(&self) -> usize {
+        self.max_chunk_size
+    }
+
+    pub fn add(&mut self, name: &str, data: &[u8]) -> Result<(), String> {
+        self.add_with(name, |writer| writer.write_all(data).unwrap())
+    }
+
+    /// Adds an entry written by `f`, which is free to seek back within
+    /// what it wrote (to fill in sizes known only at the end).
+    /// An entry larger than a chunk is cut off again, and an error.
+    pub fn add_with<T, F: FnOnce(&mut BufWriter<File>) -> T>(&mut self, name: &str, f: F) -> Result<T, String> {
+        let mut start = self.chunk.stream_position().unwrap() as usize;
+        let result = f(&mut self.chunk);
+        let mut end = self.chunk.seek(SeekFrom::End(0)).unwrap() as usize;
+
+        let size = end - start;
+        if size > self.max_chunk_size {
+            self.chunk.seek(SeekFrom::Start(start as u64)).unwrap();
+            self.chunk.get_mut().set_len(start as u64).unwrap();
+            return Err(format!("`{}` has {} bytes, over the smoosh chunk size of {}", name, size, self.max_chunk_size));
+        }
+        if end > self.max_chunk_size {
+            self.move_to_new_chunk(start, size);
+            start = 0;
+            end = size;
+        }
+        self.entries.push((name.to_string(), self.num_chunks - 1, start, end));
+        Ok(result)
+    }
+
+    // Entry sizes are only known once written, so one that overflowed
+    // its chunk is copied over to a new one and cut off from the old.
+    fn move_to_new_chunk(&mut self, start: usize, size: usize) {
+        let mut chunk = open_chunk(&self.dir, self.num_chunks);
+        self.chunk.flush().unwrap();
+        {
+            let old = self.chunk.get_mut();
+            old.seek(SeekFrom::Start(start as u64)).unwrap();
+            io::copy(&mut (&*old).take(size as u64), &mut chunk).unwrap();
+            old.set_len(start as u64).unwrap();
+        }
+        self.chunk = chunk;
+        self.num_chunks += 1;
+    }
+
+    /// Writes `meta.smoosh`, returning the number of chunks written.
+    pub fn finish(mut self, meta: &mut dyn Write) -> usize {
+        writeln!(meta, "v1,{},{}", self.max_chunk_size, self.num_chunks).unwrap();
+        self.entries.sort();
+        for (name, chunk, start, end) in &self.entries {
+            writeln!(meta, "{},{},{},{}", name, chunk, start, end).unwrap();
+        }
+        meta.flush().unwrap();
+        self.chunk.flush().unwrap();
+        self.num_chunks
+    }
+}
+
+fn open_chunk(dir: &Path, n: usize) -> BufWriter<File> {
+    BufWriter::new(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dir.join(chunk_name(n)))
+        .unwrap())
+}
+
+/// Entries of a smooshed directory, as listed in its `meta.smoosh`.
+pub struct Smooshed {
+    dir: PathBuf,
+    // chunk, start, end
+    entries: HashMap<String, (usize, u64, u64)>,
+}
+
+impl Smooshed {
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        let meta = fs::read_to_string(dir.join("meta.smoosh"))
+            .map_err(|e| format!("cannot read `{}`: {}", dir.join("meta.smoosh").display(), e))?;
+        let mut entries = HashMap::new();
+        for line in meta.lines().skip(1) {
+            let fields: Vec<&str> = line.split(',').collect();
+            let parsed = match fields.as_slice() {
+                [name, chunk, start, end] => match (chunk.parse(), start.parse(), end.parse()) {
+                    (Ok(chunk), Ok(start), Ok(end)) => Some((name.to_string(), (chunk, start, end))),
+                    _ => None,
+                },
+                _ => None,
+            };
+            let (name, entry) = parsed.ok_or(format!("invalid `meta.smoosh` line `{}`", line))?;
+            entries.insert(name, entry);
+        }
+        Ok(Smooshed{dir: dir.to_path_buf(), entries})
+    }
+
+    pub fn get(&self, name: &str) -> Option<Vec<u8>> {
+        let (chunk, start, end) = self.entries.get(name)?;
+        let mut file = File::open(self.dir.join(chunk_name(*chunk))).unwrap();
+        file.seek(SeekFrom::Start(*start)).unwrap();
+        let mut data = vec![0; (end - start) as usize];
+        file.read_exact(&mut data).unwrap();
+        Some(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_patched_after_writing_read_back_across_chunks() {
+        let dir = std::env::temp_dir().join(format!("dsp-{}-smoosh", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut smoosher = Smoosher::new(dir.clone(), 100);
+        smoosher.add("a", &[1; 60]).unwrap();
+        // A size known only once the values are written, as compressed sections are.
+        let written = smoosher.add_with("b", |writer| {
+            let start = writer.stream_position().unwrap();
+            writer.write_all(&[0; 4]).unwrap();
+            writer.write_all(&[2; 50]).unwrap();
+            writer.seek(SeekFrom::Start(start)).unwrap();
+            writer.write_all(&50u32.to_be_bytes()).unwrap();
+            50
+        });
+        assert_eq!(written, Ok(50));
+        smoosher.add("c", &[3; 40]).unwrap();
+        let mut meta = vec![];
+        assert_eq!(smoosher.finish(&mut meta), 2);
+
+        let meta = String::from_utf8(meta).unwrap();
+        assert_eq!(meta, "v1,100,2\na,0,0,60\nb,1,0,54\nc,1,54,94\n");
+        assert_eq!(fs::metadata(dir.join(chunk_name(0))).unwrap().len(), 60);
+        fs::write(dir.join("meta.smoosh"), meta).unwrap();
+        let smooshed = Smooshed::open(&dir).unwrap();
+        let b: Vec<u8> = [0, 0, 0, 50].iter().chain(&[2; 50]).cloned().collect();
+        let entries = (smooshed.get("a"), smooshed.get("b"), smooshed.get("c"), smooshed.get("d"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(entries, (Some(vec![1; 60]), Some(b), Some(vec![3; 40]), None));
+    }
+}
@@ -0,0 +1,197 @@
+use clap::ArgMatches;
+use serde_json::Value;
+
+use conf::{Aggregator, Conf, Dimension, DimensionType, InputFormat, Metric, parse_byte};
+use granularity::Granularity;
+use timestamp::parse_instant;
+
+use std::fs;
+
+/// Fills `conf` from Druid's `dataSchema` (either on its own, or wrapped in
+/// a whole ingestion spec, along with its `ioConfig` and `tuningConfig`). Options given
+/// explicitly on the command line win.
+pub fn apply(conf: &mut Conf, matches: &ArgMatches, path: &str) -> Result<(), String> {
+    let file = fs::File::open(path).map_err(|e| format!("cannot open `{}`: {}", path, e))?;
+    let spec: Value = serde_json::from_reader(file).map_err(|e| format!("invalid spec `{}`: {}", path, e))?;
+    let schema = match (spec.pointer("/spec/dataSchema"), spec.get("dataSchema")) {
+        (Some(schema), _) | (None, Some(schema)) => schema,
+        (None, None) => &spec,
+    };
+    let given = |name: &str| matches.occurrences_of(name) > 0;
+
+    if let Some(timestamp) = schema.get("timestampSpec") {
+        if let (false, Some(column)) = (given("timestamp_column"), timestamp.get("column")) {
+            conf.timestamp_column = as_str(column, "timestampSpec.column")?.to_string();
+        }
+        if let (false, Some(format)) = (given("timestamp_format"), timestamp.get("format")) {
+            conf.timestamp_format = as_str(format, "timestampSpec.format")?.parse()?;
+        }
+        match (given("timestamp_missing"), timestamp.get("missingValue")) {
+            (false, Some(Value::Null)) | (true, _) | (_, None) => (),
+            (false, Some(missing)) => {
+                conf.timestamp_missing = Some(parse_instant(as_str(missing, "timestampSpec.missingValue")?)?);
+            },
+        }
+    }
+
+    let dimensions = schema.pointer("/dimensionsSpec/dimensions").and_then(|d| d.as_array());
+    if let (false, Some(dimensions)) = (given("dimensions"), dimensions) {
+        conf.dimensions = dimensions.iter().map(|dimension| match dimension {
+            Value::String(name) => Ok(Dimension{name: name.clone(), kind: DimensionType::String}),
+            Value::Object(o) => {
+                let name = o.get("name").and_then(|n| n.as_str()).ok_or("dimension without a `name`")?;
+                let kind = match o.get("type") {
+                    Some(kind) => as_str(kind, "dimensionsSpec.dimensions[].type")?.parse()?,
+                    None => DimensionType::String,
+                };
+                Ok(Dimension{name: name.to_string(), kind})
+            },
+            _ => Err(format!("invalid dimension `{}`", dimension)),
+        }).collect::<Result<_, _>>()?;
+    }
+
+    let metrics = schema.get("metricsSpec").and_then(|m| m.as_array());
+    if let (false, Some(metrics)) = (given("metrics"), metrics) {
+        conf.metrics = metrics.iter().map(parse_metric).collect::<Result<_, _>>()?;
+    }
+
+    if let Some(granularity) = schema.get("granularitySpec") {
+        if let (false, Some(segment)) = (given("segment_granularity"), granularity.get("segmentGranularity")) {
+            conf.segment_granularity = parse_granularity(segment)?;
+        }
+        if let (false, Some(query)) = (given("query_granularity"), granularity.get("queryGranularity")) {
+            conf.query_granularity = parse_granularity(query)?;
+        }
+        if let (false, Some(rollup)) = (given("no_rollup"), granularity.get("rollup")) {
+            conf.no_rollup = !rollup.as_bool().ok_or("`granularitySpec.rollup` has to be a boolean")?;
+        }
+    }
+
+    let input = match (spec.pointer("/spec/ioConfig/inputFormat"), spec.pointer("/ioConfig/inputFormat")) {
+        (Some(input), _) | (None, Some(input)) => Some(input),
+        (None, None) => None,
+    };
+    if let Some(input) = input {
+        if let (false, Some(kind)) = (given("input_format"), input.get("type")) {
+            conf.input_format = match as_str(kind, "inputFormat.type")? {
+                "avro_ocf" => InputFormat::Avro,
+                kind => kind.parse()?,
+            };
+        }
+        if let (false, Some(delimiter)) = (given("delimiter"), input.get("delimiter")) {
+            conf.delimiter = Some(parse_byte(as_str(delimiter, "inputFormat.delimiter")?)?);
+        }
+        let from_header = input.get("findColumnsFromHeader").and_then(|f| f.as_bool()).unwrap_or(false);
+        if let (false, false, Some(columns)) = (given("columns"), from_header, input.get("columns")) {
+            conf.columns = columns.as_array().ok_or("`inputFormat.columns` has to be an array")?
+                .iter()
+                .map(|c| as_str(c, "inputFormat.columns[]").map(|c| c.to_string()))
+                .collect::<Result<_, _>>()?;
+        }
+        if let (false, Some(skip)) = (given("skip_header_rows"), input.get("skipHeaderRows")) {
+            let skip = skip.as_u64().ok_or("`inputFormat.skipHeaderRows` has to be a number")?;
+            conf.skip_header_rows = skip as usize;
+        }
+    }
+
+    let tuning = match (spec.pointer("/spec/tuningConfig"), spec.get("tuningConfig")) {
+        (Some(tuning), _) | (None, Some(tuning)) => Some(tuning),
+        (None, None) => None,
+    };
+    if let (false, Some(max)) = (given("max_rows_in_memory"), tuning.and_then(|t| t.get("maxRowsInMemory"))) {
+        let max = max.as_u64().ok_or("`tuningConfig.maxRowsInMemory` has to be a positive number")?;
+        conf.max_rows_in_memory = Some(max as usize);
+    }
+
+    if schema.get("transformSpec").is_some() {
+        warn!("`transformSpec` is not supported, ignoring");
+    }
+
+    Ok(())
+}
+
+fn as_str<'a>(value: &'a Value, name: &str) -> Result<&'a str, String> {
+    value.as_str().ok_or(format!("`{}` has to be a string", name))
+}
+
+pub fn parse_metric(metric: &Value) -> Result<Metric, String> {
+    let kind = as_str(metric.get("type").unwrap_or(&Value::Null), "metricsSpec[].type")?;
+    let name = as_str(metric.get("name").unwrap_or(&Value::Null), "metricsSpec[].name")?;
+    let aggregator = match kind {
+        "count" => Aggregator::Count,
+        "longSum" => Aggregator::LongSum,
+        "doubleSum" => Aggregator::DoubleSum,
+        "longMin" => Aggregator::LongMin,
+        "longMax" => Aggregator::LongMax,
+        "doubleMin" => Aggregator::DoubleMin,
+        "doubleMax" => Aggregator::DoubleMax,
+        "floatSum" => Aggregator::FloatSum,
+        "floatMin" => Aggregator::FloatMin,
+        "floatMax" => Aggregator::FloatMax,
+        _ => return Err(format!("unsupported aggregator `{}`", kind)),
+    };
+    let field = match (aggregator, metric.get("fieldName")) {
+        (Aggregator::Count, _) => "",
+        (_, Some(field)) => as_str(field, "metricsSpec[].fieldName")?,
+        (_, None) => name,
+    };
+    Ok(Metric{name: name.to_string(), aggregator, field: field.to_string()})
+}
+
+// Either a name (`"HOUR"`) or an object (`{"type": "period", "period": "PT1H"}`).
+pub fn parse_granularity(granularity: &Value) -> Result<Granularity, String> {
+    match granularity {
+        Value::String(name) => name.parse(),
+        Value::Object(o) => match o.get("type").and_then(|t| t.as_str()) {
+            Some("period") => {
+                let period = o.get("period").and_then(|p| p.as_str()).unwrap_or("");
+                Granularity::from_period(period).ok_or(format!("unsupported period `{}`", period))
+            },
+            Some(name) => name.parse(),
+            None => Err(format!("invalid granularity `{}`", granularity)),
+        },
+        _ => Err(format!("invalid granularity `{}`", granularity)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn ingestion_specs_fill_in_options_not_given() {
+        let spec = json!({"spec": {
+            "dataSchema": {
+                "timestampSpec": {"column": "ts", "format": "posix", "missingValue": "2020-09-14T00:00:00Z"},
+                "dimensionsSpec": {"dimensions": ["page", {"name": "bytes", "type": "long"}]},
+                "metricsSpec": [{"type": "count", "name": "rows"}, {"type": "doubleMax", "name": "slowest", "fieldName": "latency"}],
+                "granularitySpec": {"segmentGranularity": {"type": "period", "period": "PT1H"}, "queryGranularity": "SECOND", "rollup": false},
+            },
+            "ioConfig": {"inputFormat": {"type": "tsv", "columns": ["ts", "page", "bytes"], "skipHeaderRows": 2}},
+            "tuningConfig": {"maxRowsInMemory": 1000},
+        }});
+        let path = std::env::temp_dir().join(format!("dsp-{}-spec.json", std::process::id()));
+        fs::write(&path, spec.to_string()).unwrap();
+        let path = path.to_str().unwrap();
+        let matches = Conf::clap().get_matches_from(["dsp", "--spec", path, "--query-granularity", "minute", "-"]);
+        let mut conf = Conf::from_clap(&matches);
+        let applied = apply(&mut conf, &matches, path);
+        fs::remove_file(path).unwrap();
+        applied.unwrap();
+
+        assert_eq!((conf.timestamp_column.as_str(), conf.timestamp_format.name()), ("ts", "posix".to_string()));
+        assert_eq!(conf.timestamp_missing, Some(1_600_041_600_000));
+        let dimensions: Vec<_> = conf.dimensions.iter().map(|d| (d.name.as_str(), d.kind)).collect();
+        assert_eq!(dimensions, [("page", DimensionType::String), ("bytes", DimensionType::Long)]);
+        let metrics: Vec<_> = conf.metrics.iter().map(|m| (m.name.as_str(), m.aggregator, m.field.as_str())).collect();
+        assert_eq!(metrics, [("rows", Aggregator::Count, ""), ("slowest", Aggregator::DoubleMax, "latency")]);
+        assert_eq!(conf.segment_granularity, Granularity::Hour);
+        // Given on the command line.
+        assert_eq!(conf.query_granularity, Granularity::Minute);
+        assert!(conf.no_rollup);
+        assert!(conf.input_format == InputFormat::TSV);
+        assert_eq!((conf.columns.clone(), conf.skip_header_rows), (vec!["ts".to_string(), "page".to_string(), "bytes".to_string()], 2));
+        assert_eq!(conf.max_rows_in_memory, Some(1000));
+    }
+}
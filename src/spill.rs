@@ -0,0 +1,280 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use conf;
+use Data;
+
+// Runs merged at once, more are first merged in groups of that many into new runs.
+static FAN_IN: usize = 16;
+
+/// Rows persisted to disk in sorted (and rolled up) runs, one file per run.
+/// Rows of every segment interval are stored apart, in blocks, so merging
+/// runs back needs just one interval and a block of every run in memory.
+pub struct Spill {
+    dir: PathBuf,
+    // Small enough for a block of each of `FAN_IN` runs to be within `max_rows`.
+    block_rows: usize,
+    // Runs added so far.
+    runs: usize,
+    // Rows of every interval of every run.
+    sources: Vec<((i64, i64), Source)>,
+    // Runs written while merging.
+    merges: usize,
+    // Most rows a merge held in memory at once (not counting its output).
+    held: usize,
+}
+
+// Rows of one interval of a run.
+#[derive(Clone)]
+struct Source {
+    path: PathBuf,
+    start: u64,
+    blocks: usize,
+    // Written while merging, so removed once merged.
+    temporary: bool,
+}
+
+// Reads a source block by block, `row` being the first one of `block` not merged yet.
+struct Cursor {
+    reader: BufReader<File>,
+    blocks: usize,
+    block: Data,
+    row: usize,
+}
+
+impl Cursor {
+    fn open(source: &Source) -> Option<Self> {
+        let mut reader = BufReader::new(File::open(&source.path).unwrap());
+        reader.seek(SeekFrom::Start(source.start)).unwrap();
+        let mut cursor = Cursor{reader, blocks: source.blocks, block: Data::new(), row: 0};
+        if cursor.next_block() { Some(cursor) } else { None }
+    }
+
+    // Moves on to the next block, returns `false` if there are none left.
+    fn next_block(&mut self) -> bool {
+        if self.blocks == 0 {
+            return false;
+        }
+        self.blocks -= 1;
+        self.block = Data::load(&mut self.reader);
+        self.row = 0;
+        true
+    }
+}
+
+impl Spill {
+    /// Runs go to a `name`d directory in `--spill-dir`, removed once done with.
+    pub fn new(name: &str, max_rows: usize) -> Self {
+        let mut dir = conf::vals.spill_dir.clone().unwrap_or_else(std::env::temp_dir);
+        dir.push(format!("dsp-{}-{}", std::process::id(), name.replace(std::path::MAIN_SEPARATOR, "-")));
+        fs::create_dir_all(&dir).unwrap();
+        Spill{dir, block_rows: (max_rows / FAN_IN).max(1), runs: 0, sources: vec![], merges: 0, held: 0}
+    }
+
+    fn run_path(&self, n: usize) -> PathBuf {
+        self.dir.join(format!("run-{:05}", n))
+    }
+
+    /// Sorts and rolls up `data`, persisting it as a new run.
+    pub fn add(&mut self, mut data: Data) {
+        let instant = Instant::now();
+
+        data.preaggregate();
+        data.sort();
+        data.rollup();
+        let rows = data.rows();
+
+        let path = self.run_path(self.runs);
+        let mut writer = BufWriter::new(File::create(&path).unwrap());
+        for (interval, part) in data.split() {
+            let start = writer.stream_position().unwrap();
+            let blocks = persist(&mut writer, &part, self.block_rows);
+            self.sources.push((interval, Source{path: path.clone(), start, blocks, temporary: false}));
+        }
+        writer.flush().unwrap();
+        self.runs += 1;
+
+        debug!("spill `{:?}` ({} rows)", instant.elapsed(), rows);
+    }
+
+    /// Segment intervals of all the runs, in order.
+    pub fn intervals(&self) -> Vec<(i64, i64)> {
+        let mut intervals: Vec<_> = self.sources.iter().map(|(i, _)| *i).collect();
+        intervals.sort();
+        intervals.dedup();
+        intervals
+    }
+
+    /// Rows of `interval` from all the runs, merged and rolled up.
+    pub fn merged(&mut self, interval: (i64, i64)) -> Data {
+        let sources = self.sources.iter().filter(|(i, _)| *i == interval).map(|(_, s)| s.clone()).collect();
+        let mut sources = self.rewrite_as_strings(sources);
+
+        while sources.len() > FAN_IN {
+            let group: Vec<_> = sources.drain(..FAN_IN).collect();
+            let path = self.dir.join(format!("merge-{:05}", self.merges));
+            self.merges += 1;
+            let mut writer = BufWriter::new(File::create(&path).unwrap());
+            let mut blocks = 0;
+            let block_rows = self.block_rows;
+            merge(&group, &mut self.held, |part| blocks += persist(&mut writer, &part, block_rows));
+            writer.flush().unwrap();
+            remove(group);
+            sources.push(Source{path, start: 0, blocks, temporary: true});
+        }
+
+        let mut data = Data::new();
+        merge(&sources, &mut self.held, |part| data.append(part));
+        remove(sources);
+        // Rows equal to the last ones of a merged part can still come in the next.
+        data.sort_appended();
+        data.rollup();
+
+        debug!("merge held at most {} rows", self.held);
+        data
+    }
+
+    // Runs can have numbers in a column others have strings in (which it ends up as,
+    // see `Data::append`). Those are not ordered as strings, so they get turned into
+    // strings and sorted over again, as new runs (at most as large as the originals).
+    fn rewrite_as_strings(&mut self, sources: Vec<Source>) -> Vec<Source> {
+        let columns: Vec<Vec<(String, bool)>> = sources.iter()
+            .map(|source| Cursor::open(source).map(|c| c.block.numeric_columns()).unwrap_or_default())
+            .collect();
+        let strings: HashSet<&String> = columns.iter()
+            .flat_map(|c| c.iter().filter(|(_, numeric)| !numeric).map(|(key, _)| key))
+            .collect();
+
+        sources.into_iter().zip(&columns).map(|(source, columns)| {
+            let numbers: Vec<String> = columns.iter()
+                .filter(|(key, numeric)| *numeric && strings.contains(key))
+                .map(|(key, _)| key.clone())
+                .collect();
+            if numbers.is_empty() {
+                return source;
+            }
+
+            let mut data = Data::new();
+            let mut cursor = Cursor::open(&source).unwrap();
+            loop {
+                data.append(mem::take(&mut cursor.block));
+                if !cursor.next_block() {
+                    break;
+                }
+            }
+            data.make_strings(&numbers);
+
+            let path = self.dir.join(format!("merge-{:05}", self.merges));
+            self.merges += 1;
+            let mut writer = BufWriter::new(File::create(&path).unwrap());
+            let blocks = persist(&mut writer, &data, self.block_rows);
+            writer.flush().unwrap();
+            remove(vec![source]);
+            Source{path, start: 0, blocks, temporary: true}
+        }).collect()
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            warn!("could not remove `{}`: {}", self.dir.display(), e);
+        }
+    }
+}
+
+// Writes `data` in blocks of `block_rows`, returns how many.
+fn persist(writer: &mut dyn Write, data: &Data, block_rows: usize) -> usize {
+    let blocks = (data.rows() + block_rows - 1) / block_rows;
+    for n in 0..blocks {
+        data.select(n * block_rows..data.rows().min((n + 1) * block_rows)).persist(writer);
+    }
+    blocks
+}
+
+fn remove(sources: Vec<Source>) {
+    for source in sources.into_iter().filter(|s| s.temporary) {
+        fs::remove_file(&source.path).unwrap();
+    }
+}
+
+// K-way merge of `sources`, a part at a time: rows of the cursors' blocks up to
+// the least of their last rows (which no row of the blocks still to be read
+// goes before), merged and rolled up, are handed over to `sink` in order.
+fn merge<F: FnMut(Data)>(sources: &[Source], held: &mut usize, mut sink: F) {
+    let mut cursors: Vec<Cursor> = sources.iter().filter_map(Cursor::open).collect();
+    while !cursors.is_empty() {
+        let ends: Vec<usize> = {
+            let blocks: Vec<&Data> = cursors.iter().map(|c| &c.block).collect();
+            let keys = Data::sort_keys(&blocks);
+            let last = |c: usize| (blocks[c], blocks[c].rows() - 1);
+            let first = (1..blocks.len()).fold(0, |first, c| {
+                let ((a, row_a), (b, row_b)) = (last(c), last(first));
+                if a.cmp_rows_with(row_a, b, row_b, &keys) == Ordering::Less { c } else { first }
+            });
+            let (bound, bound_row) = last(first);
+            cursors.iter().map(|c| {
+                c.row + (c.row..c.block.rows())
+                    .take_while(|row| c.block.cmp_rows_with(*row, bound, bound_row, &keys) != Ordering::Greater)
+                    .count()
+            }).collect()
+        };
+
+        let parts: Vec<Data> = cursors.iter().zip(&ends)
+            .filter(|(c, end)| c.row < **end)
+            .map(|(c, end)| c.block.select(c.row..*end))
+            .collect();
+        let rows = parts.iter().map(|p| p.rows()).sum::<usize>();
+        *held = (*held).max(rows + cursors.iter().map(|c| c.block.rows()).sum::<usize>());
+        let mut part = Data::merge(parts);
+        part.rollup();
+        sink(part);
+
+        for (cursor, end) in cursors.iter_mut().zip(ends) {
+            cursor.row = end;
+        }
+        cursors.retain_mut(|c| c.row < c.block.rows() || c.next_block());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {ValVec, TIME_COLUMN};
+
+    #[test]
+    fn merge_holds_a_block_of_every_run_at_a_time() {
+        let max_rows = 64;
+        let mut spill = Spill::new("merge-test", max_rows);
+        // More runs than `FAN_IN`, with overlapping timestamps, every row coming twice.
+        for run in 0..20 {
+            let mut data = Data::new();
+            for id in run * 50..(run + 1) * 50 {
+                data.add_i(TIME_COLUMN.to_string(), id % 250 * 1000);
+                data.add_s("d".to_string(), format!("d{}", id / 250 % 2));
+                data.next_row();
+            }
+            spill.add(data);
+        }
+        let intervals = spill.intervals();
+        assert_eq!(intervals.len(), 1);
+
+        let data = spill.merged(intervals[0]);
+        assert_eq!(data.rows(), 500);
+        match &data.columns["count"] {
+            ValVec::Integer(counts, _) => assert!(counts.iter().all(|c| *c == 2)),
+            column => panic!("`count` is not an integer column: {:?}", column),
+        }
+        match &data.columns[TIME_COLUMN] {
+            ValVec::Integer(ts, _) => assert!(ts.windows(2).all(|t| t[0] <= t[1])),
+            column => panic!("`{}` is not an integer column: {:?}", TIME_COLUMN, column),
+        }
+        // A block of every merged run, and as many rows merged out of them.
+        assert!(spill.held > 0 && spill.held <= 2 * max_rows, "held {} rows", spill.held);
+    }
+}
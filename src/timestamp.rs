@@ -0,0 +1,228 @@
+extern crate chrono;
+
+use self::chrono::{DateTime, NaiveDate, NaiveDateTime};
+use serde_json::Value;
+
+use std::str::FromStr;
+
+use granularity;
+
+/// Same as Druid's `timestampSpec` formats, anything else is taken as a
+/// `chrono` pattern (e.g. `%d/%m/%Y %H:%M`).
+#[derive(Clone, Debug)]
+pub enum TimestampFormat {
+    Auto,
+    Iso,
+    Posix,
+    Millis,
+    Micro,
+    Nano,
+    Pattern(String),
+}
+
+impl TimestampFormat {
+    /// Parses `value` into milliseconds since epoch.
+    pub fn parse(&self, value: &Value) -> Option<i64> {
+        match (self, value) {
+            (TimestampFormat::Iso, Value::String(s)) |
+            (TimestampFormat::Pattern(_), Value::String(s)) |
+            (TimestampFormat::Auto, Value::String(s)) => self.parse_str(s),
+            (_, Value::String(s)) => self.parse_number(s.parse().ok()?),
+            (TimestampFormat::Iso, _) | (TimestampFormat::Pattern(_), _) => None,
+            (_, Value::Number(n)) => self.parse_number(n.as_f64()?),
+            (_, _) => None,
+        }
+    }
+
+    fn parse_number(&self, n: f64) -> Option<i64> {
+        match self {
+            TimestampFormat::Posix => Some((n * 1000.) as i64),
+            TimestampFormat::Micro => Some((n / 1000.) as i64),
+            TimestampFormat::Nano => Some((n / 1_000_000.) as i64),
+            _ => Some(n as i64),
+        }
+    }
+
+    fn parse_str(&self, s: &str) -> Option<i64> {
+        match self {
+            TimestampFormat::Pattern(pattern) => {
+                if let Ok(dt) = DateTime::parse_from_str(s, pattern) {
+                    return Some(from_naive(dt.naive_utc()));
+                }
+                if let Ok(dt) = NaiveDateTime::parse_from_str(s, pattern) {
+                    return Some(from_naive(dt));
+                }
+                NaiveDate::parse_from_str(s, pattern).ok().and_then(|d| d.and_hms_opt(0, 0, 0)).map(from_naive)
+            },
+            TimestampFormat::Auto => s.parse().ok().or_else(|| parse_iso(s)),
+            _ => parse_iso(s),
+        }
+    }
+
+    /// The format as Druid's `timestampSpec` has it: patterns are turned into Joda ones,
+    /// or into `auto` if they use anything that has no Joda counterpart.
+    pub fn name(&self) -> String {
+        match self {
+            TimestampFormat::Auto => "auto",
+            TimestampFormat::Iso => "iso",
+            TimestampFormat::Posix => "posix",
+            TimestampFormat::Millis => "millis",
+            TimestampFormat::Micro => "micro",
+            TimestampFormat::Nano => "nano",
+            TimestampFormat::Pattern(pattern) => return to_joda(pattern).unwrap_or_else(|| "auto".to_string()),
+        }.to_string()
+    }
+}
+
+// Translates a `chrono` pattern into a Joda `DateTimeFormat` one, literal letters quoted.
+fn to_joda(pattern: &str) -> Option<String> {
+    let mut joda = String::new();
+    let mut quoted = false;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let field = match c {
+            '%' => match chars.next()? {
+                'Y' => "yyyy",
+                'y' => "yy",
+                'm' => "MM",
+                'b' | 'h' => "MMM",
+                'B' => "MMMM",
+                'd' => "dd",
+                'j' => "DDD",
+                'a' => "EEE",
+                'A' => "EEEE",
+                'H' => "HH",
+                'I' => "hh",
+                'M' => "mm",
+                'S' => "ss",
+                'p' => "a",
+                'z' => "Z",
+                'F' => "yyyy-MM-dd",
+                'T' => "HH:mm:ss",
+                'R' => "HH:mm",
+                'D' => "MM/dd/yy",
+                '3' if chars.next()? == 'f' => "SSS",
+                '.' if chars.as_str().starts_with("3f") => {
+                    chars.nth(1);
+                    ".SSS"
+                },
+                ':' if chars.next()? == 'z' => "ZZ",
+                '%' => "%",
+                _ => return None,
+            },
+            // Within quotes or not, a quote is doubled.
+            '\'' => {
+                joda.push_str("''");
+                continue;
+            },
+            _ => {
+                if c.is_ascii_alphabetic() != quoted {
+                    joda.push('\'');
+                    quoted = !quoted;
+                }
+                joda.push(c);
+                continue;
+            },
+        };
+        if quoted {
+            joda.push('\'');
+            quoted = false;
+        }
+        joda.push_str(field);
+    }
+    if quoted {
+        joda.push('\'');
+    }
+    Some(joda)
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => TimestampFormat::Auto,
+            "iso" => TimestampFormat::Iso,
+            "posix" | "seconds" => TimestampFormat::Posix,
+            "millis" => TimestampFormat::Millis,
+            "micro" | "micros" => TimestampFormat::Micro,
+            "nano" | "nanos" => TimestampFormat::Nano,
+            pattern if pattern.contains('%') => TimestampFormat::Pattern(pattern.to_string()),
+            _ => return Err(format!("unknown timestamp format `{}`", s)),
+        })
+    }
+}
+
+/// Parses either milliseconds or an ISO 8601 date (used for the missing value).
+pub fn parse_instant(s: &str) -> Result<i64, String> {
+    TimestampFormat::Auto.parse_str(s).filter(|ts| granularity::in_range(*ts)).ok_or(format!("invalid timestamp `{}`", s))
+}
+
+// ISO 8601 with or without time zone (UTC assumed), or just a date.
+fn parse_iso(s: &str) -> Option<i64> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Some(from_naive(dt.naive_utc()));
+    }
+    for pattern in &["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(s, pattern) {
+            return Some(from_naive(dt));
+        }
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)).map(from_naive)
+}
+
+fn from_naive(dt: NaiveDateTime) -> i64 {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+    (dt - epoch).num_milliseconds()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(pattern: &str) -> String {
+        pattern.parse::<TimestampFormat>().unwrap().name()
+    }
+
+    #[test]
+    fn values_are_parsed_in_every_format() {
+        // 2020-09-14T10:30:00Z
+        let ts = 1_600_079_400_000;
+        let parse = |format: &str, value: Value| format.parse::<TimestampFormat>().unwrap().parse(&value);
+        assert_eq!(parse("millis", json!(ts)), Some(ts));
+        assert_eq!(parse("millis", json!(ts.to_string())), Some(ts));
+        assert_eq!(parse("posix", json!(1_600_079_400.25)), Some(ts + 250));
+        assert_eq!(parse("micro", json!(ts * 1000)), Some(ts));
+        assert_eq!(parse("nano", json!(format!("{}000000", ts))), Some(ts));
+        for iso in &["2020-09-14T10:30:00Z", "2020-09-14T12:30:00+02:00", "2020-09-14T10:30:00.000", "2020-09-14 10:30:00", "2020-09-14T10:30"] {
+            assert_eq!(parse("iso", json!(iso)), Some(ts), "{}", iso);
+            assert_eq!(parse("auto", json!(iso)), Some(ts), "{}", iso);
+        }
+        assert_eq!(parse("iso", json!("2020-09-14")), Some(ts - 37_800_000));
+        assert_eq!(parse("auto", json!(ts.to_string())), Some(ts));
+        assert_eq!(parse("%d/%m/%Y %H:%M", json!("14/09/2020 10:30")), Some(ts));
+        assert_eq!(parse("%d/%m/%Y %H:%M %z", json!("14/09/2020 11:30 +0100")), Some(ts));
+        assert_eq!(parse("%d/%m/%Y", json!("14/09/2020")), Some(ts - 37_800_000));
+
+        assert_eq!(parse("iso", json!(ts)), None);
+        assert_eq!(parse("millis", json!("yesterday")), None);
+        assert_eq!(parse("millis", json!(null)), None);
+        assert_eq!(parse_instant("2020-09-14T10:30:00Z"), Ok(ts));
+        // Past the last year there is.
+        assert!(parse_instant("99999999999999999").is_err());
+    }
+
+    #[test]
+    fn patterns_are_named_in_joda() {
+        assert_eq!(name("%d/%m/%Y %H:%M"), "dd/MM/yyyy HH:mm");
+        assert_eq!(name("%Y-%m-%dT%H:%M:%S%.3f%:z"), "yyyy-MM-dd'T'HH:mm:ss.SSSZZ");
+        assert_eq!(name("%a, %d %b %Y %T %z"), "EEE, dd MMM yyyy HH:mm:ss Z");
+        assert_eq!(name("%F at %I%p o'clock"), "yyyy-MM-dd 'at' hha 'o''clock'");
+        assert_eq!(name("%Y%%%m"), "yyyy%MM");
+        // No Joda counterparts.
+        assert_eq!(name("%s"), "auto");
+        assert_eq!(name("%H:%M:%S%.f"), "auto");
+        assert_eq!(name("%Y-%m-%d %"), "auto");
+        assert_eq!(name("millis"), "millis");
+    }
+}
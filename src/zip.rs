@@ -1,79 +1,412 @@
+extern crate chrono;
+
 use std::error::Error;
-use std::ffi::CString;
-use std::os::raw;
+use std::fs;
+use std::io::{self, BufWriter, Write};
 use std::path::PathBuf;
 
-#[link(name = "zip")]
-extern {
-    fn zip_open(path: *const raw::c_char, flags: raw::c_int, errorp: *mut raw::c_int) -> *mut raw::c_void;
-    fn zip_file_add(archive: *mut raw::c_void, name: *const raw::c_char, source: *mut raw::c_void, flags: raw::c_uint) -> raw::c_longlong;
-    fn zip_close(archive: *mut raw::c_void) -> raw::c_int;
-    fn zip_source_buffer_create(data: *const raw::c_void, len: raw::c_ulonglong, freep: raw::c_int, error: *mut raw::c_void) -> *mut raw::c_void;
-    fn zip_set_file_compression(archive: *mut raw::c_void, index: raw::c_ulonglong, comp: raw::c_int, comp_flags: raw::c_uint) -> raw::c_int;
-}
+use byteorder::{LE, WriteBytesExt};
+use self::chrono::{Datelike, Local, Timelike};
+use flate2::{Compression, Crc};
+use flate2::write::DeflateEncoder;
 
-static ZIP_CREATE: i32 = 1;
-static ZIP_TRUNCATE: i32 = 8;
-static ZIP_CM_DEFLATE: i32 = 8;
+static LOCAL_HEADER: u32 = 0x0403_4b50;
+static DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+static CENTRAL_HEADER: u32 = 0x0201_4b50;
+static END_OF_CENTRAL: u32 = 0x0605_4b50;
+static ZIP64_END_OF_CENTRAL: u32 = 0x0606_4b50;
+static ZIP64_LOCATOR: u32 = 0x0706_4b50;
+
+static VERSION_DEFLATE: u16 = 20;
+static VERSION_ZIP64: u16 = 45;
+// Sizes and crc follow the data, names are UTF-8.
+static FLAGS: u16 = 1 << 3 | 1 << 11;
+static METHOD_DEFLATE: u16 = 8;
 
 #[derive(Debug)]
-pub struct ZipError(&'static str);
+pub enum ZipError {
+    Io(io::Error),
+    TooLarge(String),
+}
 
 impl std::fmt::Display for ZipError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Zip Error: `{}`", self.0)
+        match self {
+            ZipError::Io(e) => write!(f, "Zip Error: `{}`", e),
+            ZipError::TooLarge(name) => write!(f, "Zip Error: `{}` is over 4GiB", name),
+        }
     }
 }
 
 impl Error for ZipError {}
 
-pub struct Zip {
-    ptr: *mut raw::c_void,
-    compression: u8,
+impl From<io::Error> for ZipError {
+    fn from(e: io::Error) -> Self {
+        ZipError::Io(e)
+    }
+}
+
+struct Counter<W: Write> {
+    inner: W,
+    count: u64,
 }
 
-impl Zip {
-    pub fn new(path: PathBuf, compression: u8) -> Result<Self, ZipError> {
-        let mut errorp = 0;
-        unsafe {
-            let cpath = CString::new(path.to_str().unwrap()).unwrap();
-            let zip = zip_open(cpath.as_ptr(), ZIP_CREATE | ZIP_TRUNCATE, &mut errorp);
-            if zip.is_null() {
-                return Result::Err(ZipError("Could not open os file"));
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct Header {
+    name: String,
+    crc: u32,
+    compressed: u32,
+    uncompressed: u32,
+    offset: u64,
+}
+
+/// Deflating zip archive writer, entries are streamed one after another
+/// (with their sizes in data descriptors), so nothing is held in memory
+/// beyond the deflate window.
+pub struct Zip<W: Write> {
+    out: Counter<W>,
+    level: Compression,
+    time: u16,
+    date: u16,
+    headers: Vec<Header>,
+}
+
+impl Zip<BufWriter<fs::File>> {
+    pub fn create(path: PathBuf, level: u8) -> Result<Self, ZipError> {
+        Ok(Zip::new(BufWriter::new(fs::File::create(path)?), level))
+    }
+}
+
+impl<W: Write> Zip<W> {
+    pub fn new(out: W, level: u8) -> Self {
+        // MS-DOS format, local time with 2 seconds precision.
+        let now = Local::now();
+        let time = (now.hour() << 11 | now.minute() << 5 | (now.second() / 2)) as u16;
+        let date = ((now.year().max(1980) - 1980) << 9) as u16 | (now.month() << 5 | now.day()) as u16;
+        Zip{out: Counter{inner: out, count: 0}, level: Compression::new(level.into()), time, date, headers: vec![]}
+    }
+
+    /// Starts a new entry, written to through the returned `ZipEntry`
+    /// until its `finish`.
+    pub fn start_file(&mut self, name: &str) -> Result<ZipEntry<'_, W>, ZipError> {
+        let offset = self.out.count;
+        let out = &mut self.out;
+        out.write_u32::<LE>(LOCAL_HEADER)?;
+        out.write_u16::<LE>(VERSION_DEFLATE)?;
+        out.write_u16::<LE>(FLAGS)?;
+        out.write_u16::<LE>(METHOD_DEFLATE)?;
+        out.write_u16::<LE>(self.time)?;
+        out.write_u16::<LE>(self.date)?;
+        out.write_u32::<LE>(0)?; // crc
+        out.write_u32::<LE>(0)?; // compressed size
+        out.write_u32::<LE>(0)?; // uncompressed size
+        out.write_u16::<LE>(name.len() as u16)?;
+        out.write_u16::<LE>(0)?; // extra length
+        out.write_all(name.as_bytes())?;
+
+        let start = out.count;
+        Ok(ZipEntry{
+            name: name.to_string(),
+            offset,
+            start,
+            size: 0,
+            crc: Crc::new(),
+            encoder: DeflateEncoder::new(out, self.level),
+            headers: &mut self.headers,
+        })
+    }
+
+    pub fn file_add(&mut self, name: &str, data: &[u8]) -> Result<(), ZipError> {
+        let mut entry = self.start_file(name)?;
+        entry.write_all(data)?;
+        entry.finish()
+    }
+
+    /// Writes the central directory, returning the underlying writer (flushed).
+    pub fn finish(mut self) -> Result<W, ZipError> {
+        let start = self.out.count;
+        let out = &mut self.out;
+        for header in &self.headers {
+            let zip64 = header.offset >= u64::from(u32::MAX);
+            out.write_u32::<LE>(CENTRAL_HEADER)?;
+            out.write_u16::<LE>(if zip64 { VERSION_ZIP64 } else { VERSION_DEFLATE })?; // made by
+            out.write_u16::<LE>(if zip64 { VERSION_ZIP64 } else { VERSION_DEFLATE })?; // needed
+            out.write_u16::<LE>(FLAGS)?;
+            out.write_u16::<LE>(METHOD_DEFLATE)?;
+            out.write_u16::<LE>(self.time)?;
+            out.write_u16::<LE>(self.date)?;
+            out.write_u32::<LE>(header.crc)?;
+            out.write_u32::<LE>(header.compressed)?;
+            out.write_u32::<LE>(header.uncompressed)?;
+            out.write_u16::<LE>(header.name.len() as u16)?;
+            out.write_u16::<LE>(if zip64 { 12 } else { 0 })?; // extra length
+            out.write_u16::<LE>(0)?; // comment length
+            out.write_u16::<LE>(0)?; // disk
+            out.write_u16::<LE>(0)?; // internal attributes
+            out.write_u32::<LE>(0)?; // external attributes
+            out.write_u32::<LE>(if zip64 { u32::MAX } else { header.offset as u32 })?;
+            out.write_all(header.name.as_bytes())?;
+            if zip64 {
+                out.write_u16::<LE>(0x1)?; // Zip64 extended information
+                out.write_u16::<LE>(8)?;
+                out.write_u64::<LE>(header.offset)?;
             }
-            Result::Ok(Self{ptr: zip, compression})
         }
+        let end = out.count;
+        let size = end - start;
+        let entries = self.headers.len() as u64;
+
+        let zip64 = entries >= 0xffff || end >= u64::from(u32::MAX);
+        if zip64 {
+            out.write_u32::<LE>(ZIP64_END_OF_CENTRAL)?;
+            out.write_u64::<LE>(44)?; // size of the rest of this record
+            out.write_u16::<LE>(VERSION_ZIP64)?;
+            out.write_u16::<LE>(VERSION_ZIP64)?;
+            out.write_u32::<LE>(0)?; // this disk
+            out.write_u32::<LE>(0)?; // central directory disk
+            out.write_u64::<LE>(entries)?;
+            out.write_u64::<LE>(entries)?;
+            out.write_u64::<LE>(size)?;
+            out.write_u64::<LE>(start)?;
+
+            out.write_u32::<LE>(ZIP64_LOCATOR)?;
+            out.write_u32::<LE>(0)?; // disk with the zip64 end of central directory
+            out.write_u64::<LE>(end)?;
+            out.write_u32::<LE>(1)?; // total disks
+        }
+        out.write_u32::<LE>(END_OF_CENTRAL)?;
+        out.write_u16::<LE>(0)?; // this disk
+        out.write_u16::<LE>(0)?; // central directory disk
+        out.write_u16::<LE>(if zip64 { 0xffff } else { entries as u16 })?;
+        out.write_u16::<LE>(if zip64 { 0xffff } else { entries as u16 })?;
+        out.write_u32::<LE>(if zip64 { u32::MAX } else { size as u32 })?;
+        out.write_u32::<LE>(if zip64 { u32::MAX } else { start as u32 })?;
+        out.write_u16::<LE>(0)?; // comment length
+        out.flush()?;
+        Ok(self.out.inner)
+    }
+}
+
+/// A single, deflated, zip entry.
+pub struct ZipEntry<'a, W: Write> {
+    name: String,
+    offset: u64,
+    start: u64,
+    size: u64,
+    crc: Crc,
+    encoder: DeflateEncoder<&'a mut Counter<W>>,
+    headers: &'a mut Vec<Header>,
+}
+
+impl<'a, W: Write> ZipEntry<'a, W> {
+    /// Completes the compressed stream and writes its data descriptor.
+    pub fn finish(self) -> Result<(), ZipError> {
+        let out = self.encoder.finish()?;
+        let compressed = out.count - self.start;
+        let uncompressed = self.size;
+        if compressed > u64::from(u32::MAX) || uncompressed > u64::from(u32::MAX) {
+            return Err(ZipError::TooLarge(self.name));
+        }
+        out.write_u32::<LE>(DATA_DESCRIPTOR)?;
+        out.write_u32::<LE>(self.crc.sum())?;
+        out.write_u32::<LE>(compressed as u32)?;
+        out.write_u32::<LE>(uncompressed as u32)?;
+
+        self.headers.push(Header{
+            name: self.name,
+            crc: self.crc.sum(),
+            compressed: compressed as u32,
+            uncompressed: uncompressed as u32,
+            offset: self.offset,
+        });
+        Ok(())
+    }
+}
+
+impl<'a, W: Write> Write for ZipEntry<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.encoder.write(buf)?;
+        self.crc.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.encoder.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ByteOrder;
+    use flate2::read::DeflateDecoder;
+    use std::io::Read;
+
+    struct Archive {
+        entries: Vec<(String, u32, Vec<u8>)>,
+        zip64: bool,
     }
 
-    pub fn file_add(&self, name: &str, data: &[u8]) -> Result<(), ZipError> {
-        let mut error = vec![]; // Not used, but have to bring sth valid to the call
-        unsafe {
-            let source = zip_source_buffer_create(
-                data.as_ptr() as *const raw::c_void,
-                data.len() as u64,
-                0,
-                error.as_mut_ptr() as *mut raw::c_void,
-            );
-            if source.is_null() {
-                return Result::Err(ZipError("Could not allocate memort for source"));
+    // Reads `archive` back from its central directory, checking local headers and
+    // data descriptors against it. Offsets in the archive are `base` past its start.
+    fn read(archive: &[u8], base: u64) -> Archive {
+        let u16_at = |at: usize| LE::read_u16(&archive[at..]);
+        let u32_at = |at: usize| LE::read_u32(&archive[at..]);
+        let u64_at = |at: usize| LE::read_u64(&archive[at..]);
+        let position = |offset: u64| (offset - base) as usize;
+
+        let end = archive.len() - 22;
+        assert_eq!(u32_at(end), END_OF_CENTRAL);
+        let (mut entries, mut start) = (u64::from(u16_at(end + 10)), u64::from(u32_at(end + 16)));
+        let zip64 = entries == 0xffff || start == u64::from(u32::MAX);
+        if zip64 {
+            let locator = end - 20;
+            assert_eq!(u32_at(locator), ZIP64_LOCATOR);
+            let record = position(u64_at(locator + 8));
+            assert_eq!(u32_at(record), ZIP64_END_OF_CENTRAL);
+            assert_eq!(record + 56, locator);
+            entries = u64_at(record + 32);
+            start = u64_at(record + 48);
+        }
+
+        let mut at = position(start);
+        let entries = (0..entries).map(|_| {
+            assert_eq!(u32_at(at), CENTRAL_HEADER);
+            assert_eq!(u16_at(at + 8), FLAGS);
+            assert_eq!(u16_at(at + 10), METHOD_DEFLATE);
+            let (crc, compressed, uncompressed) = (u32_at(at + 16), u32_at(at + 20), u32_at(at + 24));
+            let (name_len, extra_len) = (u16_at(at + 28) as usize, u16_at(at + 30) as usize);
+            let name = String::from_utf8(archive[at + 46..at + 46 + name_len].to_vec()).unwrap();
+            let mut offset = u64::from(u32_at(at + 42));
+            if offset == u64::from(u32::MAX) {
+                let extra = at + 46 + name_len;
+                assert_eq!((u16_at(extra), u16_at(extra + 2)), (0x1, 8));
+                offset = u64_at(extra + 4);
             }
+            at += 46 + name_len + extra_len;
+
+            let local = position(offset);
+            assert_eq!(u32_at(local), LOCAL_HEADER);
+            assert_eq!(u16_at(local + 6), FLAGS);
+            assert_eq!(u16_at(local + 8), METHOD_DEFLATE);
+            assert_eq!(&archive[local + 30..local + 30 + name_len], name.as_bytes());
+            let data = local + 30 + name_len + u16_at(local + 28) as usize;
+            let descriptor = data + compressed as usize;
+            assert_eq!(u32_at(descriptor), DATA_DESCRIPTOR);
+            assert_eq!((u32_at(descriptor + 4), u32_at(descriptor + 8), u32_at(descriptor + 12)), (crc, compressed, uncompressed));
+
+            let mut inflated = vec![];
+            DeflateDecoder::new(&archive[data..descriptor]).read_to_end(&mut inflated).unwrap();
+            assert_eq!(inflated.len(), uncompressed as usize);
+            let mut check = Crc::new();
+            check.update(&inflated);
+            assert_eq!(check.sum(), crc, "crc of `{}`", name);
+            (name, crc, inflated)
+        }).collect();
+        assert_eq!(at, end - if zip64 { 76 } else { 0 });
+        Archive{entries, zip64}
+    }
+
+    #[test]
+    fn entries_read_back() {
+        let text: Vec<u8> = (0..50_000).flat_map(|n| format!("{{\"page\": \"p{}\"}}\n", n % 300).into_bytes()).collect();
+        let noise = ::tests::noise(200_000);
 
-            let cname = CString::new(name).unwrap();
-            let idx = zip_file_add(self.ptr, cname.as_ptr(), source, 0);
-            if idx < 0 {
-                return Result::Err(ZipError("Could not allocate memory for file"));
+        for level in &[1, 9] {
+            let mut zip = Zip::new(vec![], *level);
+            zip.file_add("empty", b"").unwrap();
+            zip.file_add("check", b"123456789").unwrap();
+            zip.file_add("dir/text.json", &text).unwrap();
+            {
+                let mut entry = zip.start_file("dir/noise-ü").unwrap();
+                for chunk in noise.chunks(7_777) {
+                    entry.write_all(chunk).unwrap();
+                }
+                entry.finish().unwrap();
             }
-            zip_set_file_compression(self.ptr, idx as u64, ZIP_CM_DEFLATE, self.compression.into());
-            Result::Ok(())
+            let archive = read(&zip.finish().unwrap(), 0);
+
+            assert!(!archive.zip64);
+            let names: Vec<&str> = archive.entries.iter().map(|(name, _, _)| name.as_str()).collect();
+            assert_eq!(names, ["empty", "check", "dir/text.json", "dir/noise-ü"]);
+            // The standard CRC-32 check value.
+            assert_eq!(archive.entries[1].1, 0xcbf4_3926);
+            assert_eq!(archive.entries[0].2, b"");
+            assert_eq!(archive.entries[1].2, b"123456789");
+            assert_eq!(archive.entries[2].2, text);
+            assert_eq!(archive.entries[3].2, noise);
         }
     }
 
-    pub fn close(&self) -> Result<(), ZipError> {
-        unsafe {
-            if zip_close(self.ptr) < 0 {
-                return Result::Err(ZipError("Could not write archive"));
+    #[test]
+    fn zip64_records_are_written_for_too_many_entries() {
+        for (entries, zip64) in &[(0xfffe, false), (0xffff, true)] {
+            let mut zip = Zip::new(vec![], 1);
+            zip.file_add("entry", b"data").unwrap();
+            // Deflating that many entries takes a while, the rest share the first one's data.
+            let (crc, compressed, uncompressed) = (zip.headers[0].crc, zip.headers[0].compressed, zip.headers[0].uncompressed);
+            for _ in 1..*entries {
+                zip.headers.push(Header{name: "entry".to_string(), crc, compressed, uncompressed, offset: 0});
             }
+            let archive = read(&zip.finish().unwrap(), 0);
+            assert_eq!(archive.zip64, *zip64);
+            assert_eq!(archive.entries.len(), *entries);
+            assert!(archive.entries.iter().all(|(name, _, data)| name == "entry" && data == b"data"));
         }
-        Result::Ok(())
+    }
+
+    #[test]
+    fn zip64_records_are_written_for_offsets_past_4gib() {
+        let mut zip = Zip::new(vec![], 9);
+        // As if 4GiB had been written already.
+        let base = u64::from(u32::MAX) - 10;
+        zip.out.count = base;
+        zip.file_add("below", b"offset within 32 bits").unwrap();
+        zip.file_add("above", b"offset past 32 bits").unwrap();
+        let archive = zip.finish().unwrap();
+        assert_eq!(LE::read_u32(&archive[archive.len() - 6..]), u32::MAX);
+
+        let archive = read(&archive, base);
+        assert!(archive.zip64);
+        assert_eq!(archive.entries[0].2, b"offset within 32 bits");
+        assert_eq!(archive.entries[1].2, b"offset past 32 bits");
+    }
+
+    #[test]
+    fn zip64_records_are_written_for_offsets_at_4gib() {
+        // An offset of exactly 0xFFFFFFFF is the sentinel itself, so it can only be held in zip64.
+        let base = u64::from(u32::MAX);
+        let mut zip = Zip::new(vec![], 9);
+        zip.out.count = base;
+        zip.file_add("at", b"offset at 32 bits").unwrap();
+        let archive = zip.finish().unwrap();
+
+        let archive = read(&archive, base);
+        assert!(archive.zip64);
+        assert_eq!(archive.entries[0].2, b"offset at 32 bits");
+
+        // The central directory of an empty entry (53 bytes, with its local header) is 51
+        // bytes, ending one short of the sentinel.
+        let base = u64::from(u32::MAX) - 105;
+        let mut zip = Zip::new(vec![], 9);
+        zip.out.count = base;
+        zip.file_add("below", b"").unwrap();
+        let archive = zip.finish().unwrap();
+        assert_eq!(archive.len(), 104 + 22);
+
+        let archive = read(&archive, base);
+        assert!(!archive.zip64);
     }
 }